@@ -0,0 +1,436 @@
+use crate::config::{Binding, Config, Endpoint, CHANNEL_PREFIXES};
+use failure::{format_err, Fallible};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+};
+
+/// The longest an IRC channel name may be, per RFC 2812.
+const MAX_CHANNEL_LEN: usize = 50;
+
+/// Checks the config file for mistakes without connecting to anything, printing any problems and
+/// the resulting routing table. Fails if there were any errors.
+pub fn run(path: &Path) -> Fallible<()> {
+    Config::reload_from(path)?;
+
+    let problems = check_irc_config()
+        .into_iter()
+        .chain(check_bindings(&Config::bindings()))
+        .collect::<Vec<_>>();
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        println!();
+    }
+    print_routing_table();
+
+    let errors = problems.iter().filter(|p| p.is_error).count();
+    if errors == 0 {
+        Ok(())
+    } else {
+        Err(format_err!("{} found {} error(s)", path.display(), errors))
+    }
+}
+
+/// A mistake found in the config.
+struct Problem {
+    /// Whether the mistake stops Janus from working properly, rather than just being suspicious.
+    is_error: bool,
+
+    /// A description of the mistake.
+    message: String,
+}
+
+impl Problem {
+    fn error(message: String) -> Problem {
+        Problem {
+            is_error: true,
+            message,
+        }
+    }
+
+    fn warning(message: String) -> Problem {
+        Problem {
+            is_error: false,
+            message,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let level = if self.is_error { "error" } else { "warning" };
+        write!(fmt, "{}: {}", level, self.message)
+    }
+}
+
+/// Checks each IRC network has what's needed to connect.
+fn check_irc_config() -> Vec<Problem> {
+    let networks = Config::irc_networks();
+    if networks.is_empty() {
+        return vec![Problem::error("no IRC networks are configured".to_string())];
+    }
+
+    let mut problems = Vec::new();
+    for name in networks {
+        let network = Config::irc_network(&name).unwrap();
+        if network.config.nickname.is_none() {
+            problems.push(Problem::error(format!("{} has no nickname", name)));
+        }
+        if network.config.server.is_none() {
+            problems.push(Problem::error(format!("{} has no server", name)));
+        }
+    }
+    problems
+}
+
+/// Returns the name to show for a channel. IRC channels only include their network if there are
+/// several.
+fn endpoint_name(endpoint: &Endpoint) -> String {
+    match endpoint {
+        Endpoint::Discord(id) => id.to_string(),
+        Endpoint::Irc(network, channel) if Config::irc_networks().len() > 1 => {
+            format!("{}/{}", network, channel)
+        }
+        Endpoint::Irc(_, channel) => channel.clone(),
+    }
+}
+
+/// Returns a name for a channel that is the same however an IRC channel's name is capitalised, to
+/// sort and group channels by.
+fn endpoint_key(endpoint: &Endpoint) -> String {
+    endpoint_name(endpoint).to_ascii_lowercase()
+}
+
+/// Checks the bindings for invalid values, and for ones that duplicate or conflict with each other.
+fn check_bindings(bindings: &[Binding]) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for binding in bindings {
+        let names = binding
+            .members
+            .iter()
+            .map(|m| endpoint_name(&m.endpoint))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        for member in &binding.members {
+            if let Endpoint::Irc(_, channel) = &member.endpoint {
+                if let Err(why) = check_channel_name(channel) {
+                    problems.push(Problem::error(format!(
+                        "{:?} isn't a valid IRC channel name: {}",
+                        endpoint_name(&member.endpoint),
+                        why
+                    )));
+                }
+            }
+            if !member.send && !member.receive {
+                problems.push(Problem::warning(format!(
+                    "{} neither sends nor receives in the binding of {}",
+                    endpoint_name(&member.endpoint),
+                    names
+                )));
+            }
+        }
+        for (i, member) in binding.members.iter().enumerate() {
+            if binding.members[..i]
+                .iter()
+                .any(|m| m.endpoint == member.endpoint)
+            {
+                problems.push(Problem::error(format!(
+                    "{} is bound to itself, in the binding of {}",
+                    endpoint_name(&member.endpoint),
+                    names
+                )));
+            }
+        }
+        if binding.members.len() < 2 {
+            problems.push(Problem::warning(format!(
+                "the binding of {} has nothing to relay to",
+                names
+            )));
+        }
+        for mention in &binding.allowed_mentions {
+            if mention != "everyone" && mention != "here" {
+                problems.push(Problem::error(format!(
+                    "the binding of {} allows {:?}, which isn't \"everyone\" or \"here\"",
+                    names, mention
+                )));
+            }
+        }
+    }
+
+    let mut pairs = HashMap::new();
+    for (i, binding) in bindings.iter().enumerate() {
+        for a in &binding.members {
+            for b in &binding.members {
+                if endpoint_key(&a.endpoint) >= endpoint_key(&b.endpoint) {
+                    continue;
+                }
+                let key = (a.endpoint.clone(), b.endpoint.clone());
+                match pairs.get(&key) {
+                    Some(&j) if j != i => problems.push(Problem::error(format!(
+                        "{} and {} are bound more than once",
+                        endpoint_name(&a.endpoint),
+                        endpoint_name(&b.endpoint)
+                    ))),
+                    _ => {
+                        pairs.insert(key, i);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut bindings_by_endpoint = BTreeMap::<String, (String, usize)>::new();
+    for binding in bindings {
+        let endpoints = binding
+            .members
+            .iter()
+            .map(|m| (endpoint_key(&m.endpoint), endpoint_name(&m.endpoint)))
+            .collect::<BTreeMap<_, _>>();
+        for (key, name) in endpoints {
+            bindings_by_endpoint.entry(key).or_insert((name, 0)).1 += 1;
+        }
+    }
+    for (name, count) in bindings_by_endpoint.values() {
+        if *count > 1 {
+            problems.push(Problem::warning(format!(
+                "{} is in {} bindings, whose other channels won't see each other's messages",
+                name, count
+            )));
+        }
+    }
+
+    let mut discord_by_webhook = HashMap::<&str, &Endpoint>::new();
+    for member in bindings.iter().flat_map(|b| &b.members) {
+        if let Some(webhook) = &member.webhook {
+            match discord_by_webhook.get(webhook.as_str()) {
+                Some(&endpoint) if *endpoint != member.endpoint => {
+                    problems.push(Problem::error(format!(
+                        "{} and {} share a webhook, but a webhook can only post to one channel",
+                        endpoint_name(endpoint),
+                        endpoint_name(&member.endpoint)
+                    )));
+                }
+                _ => {
+                    discord_by_webhook.insert(webhook, &member.endpoint);
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+/// Checks that a string is a valid IRC channel name.
+fn check_channel_name(name: &str) -> Result<(), &'static str> {
+    if !name.starts_with(CHANNEL_PREFIXES) {
+        Err("it must start with #, &, + or !")
+    } else if name.len() < 2 {
+        Err("it is empty after the prefix")
+    } else if name.len() > MAX_CHANNEL_LEN {
+        Err("it is longer than 50 bytes")
+    } else if name.contains(&[' ', ',', ':', '\x07'][..]) {
+        Err("it contains a space, comma, colon or bell")
+    } else if name.contains(char::is_control) {
+        Err("it contains a control character")
+    } else {
+        Ok(())
+    }
+}
+
+/// Prints where messages from each channel are relayed to.
+fn print_routing_table() {
+    let mut endpoints = Config::bindings()
+        .into_iter()
+        .flat_map(|b| b.members)
+        .map(|m| m.endpoint)
+        .collect::<Vec<_>>();
+    endpoints.sort_by_key(endpoint_key);
+    endpoints.dedup();
+
+    println!("Routing table:");
+    for from in endpoints {
+        let to = Config::routes_from(&from)
+            .into_iter()
+            .map(|(_, m)| endpoint_name(&m.endpoint))
+            .collect::<Vec<_>>();
+        if !to.is_empty() {
+            println!("  {} -> {}", endpoint_name(&from), to.join(", "));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Member;
+
+    fn member(endpoint: Endpoint) -> Member {
+        Member {
+            endpoint,
+            webhook: None,
+            send: true,
+            receive: true,
+        }
+    }
+
+    fn irc(channel: &str) -> Member {
+        member(Endpoint::Irc("irc".to_string(), channel.to_string()))
+    }
+
+    fn discord(id: u64) -> Member {
+        member(Endpoint::Discord(id))
+    }
+
+    fn binding(members: Vec<Member>) -> Binding {
+        let mut binding = Binding::new(1, "#chan".to_string());
+        binding.members = members;
+        binding
+    }
+
+    /// Returns the problems found in the bindings, as they would be printed.
+    fn problems(bindings: &[Binding]) -> Vec<String> {
+        check_bindings(bindings)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn valid_channel_names() {
+        for name in &[
+            "#rust",
+            "&local",
+            "+modeless",
+            "!ABCDEsafe",
+            "#a.b-c_d",
+            "#ünicode",
+        ] {
+            assert_eq!(check_channel_name(name), Ok(()), "{}", name);
+        }
+        assert_eq!(check_channel_name(&format!("#{}", "a".repeat(49))), Ok(()));
+    }
+
+    #[test]
+    fn invalid_channel_names() {
+        for name in &[
+            "rust",
+            "#",
+            "#two words",
+            "#a,b",
+            "#a:b",
+            "#bell\x07",
+            "#tab\t",
+        ] {
+            assert!(check_channel_name(name).is_err(), "{:?}", name);
+        }
+        assert!(check_channel_name(&format!("#{}", "a".repeat(50))).is_err());
+    }
+
+    #[test]
+    fn distinct_bindings_are_fine() {
+        let bindings = [
+            binding(vec![discord(1), irc("#a")]),
+            binding(vec![discord(2), irc("#b"), irc("#c")]),
+        ];
+        assert_eq!(problems(&bindings), Vec::<String>::new());
+    }
+
+    #[test]
+    fn bad_members_are_reported() {
+        let mut lonely = binding(vec![discord(1)]);
+        lonely.allowed_mentions = vec!["mods".to_string()];
+        let mut mute = irc("#b");
+        mute.send = false;
+        mute.receive = false;
+        assert_eq!(
+            problems(&[
+                lonely,
+                binding(vec![discord(2), irc("no-prefix")]),
+                binding(vec![discord(3), mute]),
+                binding(vec![discord(4), irc("#d"), irc("#d")]),
+            ]),
+            vec![
+                "warning: the binding of 1 has nothing to relay to",
+                "error: the binding of 1 allows \"mods\", which isn't \"everyone\" or \"here\"",
+                "error: \"no-prefix\" isn't a valid IRC channel name: it must start with #, &, + \
+                 or !",
+                "warning: #b neither sends nor receives in the binding of 3, #b",
+                "error: #d is bound to itself, in the binding of 4, #d, #d",
+            ]
+        );
+    }
+
+    #[test]
+    fn channels_in_several_bindings_are_warnings() {
+        assert_eq!(
+            problems(&[
+                binding(vec![discord(1), irc("#a")]),
+                binding(vec![discord(1), irc("#b")]),
+            ]),
+            vec![
+                "warning: 1 is in 2 bindings, whose other channels won't see each other's messages"
+            ]
+        );
+    }
+
+    #[test]
+    fn channels_bound_in_different_directions_are_accepted() {
+        // The layout of janus-test.toml, where one IRC channel is bound to a Discord channel each
+        // way, and to another that only receives from it and one that only sends to it.
+        let one_way = |mut a: Member, mut b: Member| {
+            a.receive = false;
+            b.send = false;
+            binding(vec![a, b])
+        };
+        let problems = check_bindings(&[
+            binding(vec![discord(1), irc("#janus-test")]),
+            one_way(irc("#janus-test"), discord(2)),
+            one_way(discord(3), irc("#janus-test")),
+        ]);
+        assert!(problems.iter().all(|p| !p.is_error));
+    }
+
+    #[test]
+    fn irc_channels_are_matched_case_insensitively() {
+        assert_eq!(
+            problems(&[
+                binding(vec![discord(1), irc("#Rust")]),
+                binding(vec![discord(2), irc("#rust")]),
+                binding(vec![discord(3), irc("#a"), irc("#A")]),
+            ]),
+            vec![
+                "error: #A is bound to itself, in the binding of 3, #a, #A",
+                "warning: #Rust is in 2 bindings, whose other channels won't see each other's \
+                 messages",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_pairs_are_errors() {
+        let problems = problems(&[
+            binding(vec![discord(1), irc("#a")]),
+            binding(vec![irc("#a"), discord(1)]),
+        ]);
+        assert!(problems.contains(&"error: #a and 1 are bound more than once".to_string()));
+    }
+
+    #[test]
+    fn shared_webhooks_are_errors() {
+        let hooked = |id| Member {
+            webhook: Some("https://discordapp.com/api/webhooks/1/token".to_string()),
+            ..discord(id)
+        };
+        let problems = problems(&[
+            binding(vec![hooked(1), irc("#a")]),
+            binding(vec![hooked(2), irc("#b")]),
+        ]);
+        assert_eq!(
+            problems,
+            vec!["error: 1 and 2 share a webhook, but a webhook can only post to one channel"]
+        );
+    }
+}
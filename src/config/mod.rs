@@ -1,48 +1,102 @@
 #[cfg(feature = "signals")]
 mod signals;
 
-use failure::Fallible;
+use failure::{format_err, Fallible};
 use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
 use irc::client::data::config::Config as IrcConfig;
 use lazy_static::lazy_static;
 use log::warn;
+use serde::{Deserialize, Deserializer};
 use std::{
+    collections::BTreeMap,
     fs::File,
+    hash::{Hash, Hasher},
     io::Read,
     path::{Path, PathBuf},
     sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 lazy_static! {
     static ref CONFIG: Arc<RwLock<Config>> = Arc::new(RwLock::new(Config {
-        irc: IrcConfig::default(),
+        irc: BTreeMap::new(),
         bindings: Vec::new(),
+        state_dir: default_state_dir(),
+        message_retention_hours: default_message_retention_hours(),
+        max_queued_messages: default_max_queued_messages(),
+        part_message: None,
+        keep_channels: Vec::new(),
+        coalesce_ms: default_coalesce_ms(),
+        paste: None,
     }));
     static ref NOTIFY_MES: Arc<Mutex<Vec<UnboundedSender<()>>>> = Arc::new(Mutex::new(Vec::new()));
 }
 
-/// The configuration for a bridge between a Discord server and an IRC server.
+/// The name of the network configured by a plain `[irc]` section.
+pub const DEFAULT_NETWORK: &str = "irc";
+
+/// The characters IRC channel names may start with.
+pub const CHANNEL_PREFIXES: &[char] = &['#', '&', '+', '!'];
+
+/// The configuration for a bridge between a Discord server and IRC servers.
 #[derive(Deserialize)]
 pub struct Config {
-    /// IRC configuration.
-    irc: IrcConfig,
+    /// The IRC networks to connect to, by name. A plain `[irc]` section configures a single
+    /// network, while `[irc.<name>]` sections configure several.
+    #[serde(deserialize_with = "deserialize_networks")]
+    irc: BTreeMap<String, IrcNetwork>,
 
     /// Bindings between two channels.
     bindings: Vec<Binding>,
+
+    /// The directory to keep persistent state in.
+    #[serde(default = "default_state_dir")]
+    state_dir: PathBuf,
+
+    /// How long to remember which messages were relayed where, in hours.
+    #[serde(default = "default_message_retention_hours")]
+    message_retention_hours: u64,
+
+    /// The most messages to hold for a side of the relay while it is disconnected.
+    #[serde(default = "default_max_queued_messages")]
+    max_queued_messages: usize,
+
+    /// The message to leave IRC channels with when their bindings are removed.
+    part_message: Option<String>,
+
+    /// IRC channels to stay in even though they aren't bound, such as ones the bot was invited to.
+    #[serde(default)]
+    keep_channels: Vec<String>,
+
+    /// How long to wait for more lines from an IRC user before posting them to Discord as one
+    /// message, in milliseconds. Zero posts every line separately.
+    #[serde(default = "default_coalesce_ms")]
+    coalesce_ms: u64,
+
+    /// Where to put messages too long to relay, if anywhere.
+    paste: Option<PasteConfig>,
 }
 
 impl Config {
-    /// Returns the Discord channels that should be sent messages from the named IRC channel.
-    pub fn discord_for_irc(irc: String) -> Vec<u64> {
+    /// Returns all the bindings.
+    pub fn bindings() -> Vec<Binding> {
+        CONFIG.read().unwrap().bindings.clone()
+    }
+
+    /// Returns how long to wait for more lines from an IRC user before posting them to Discord.
+    pub fn coalesce_window() -> Duration {
+        Duration::from_millis(CONFIG.read().unwrap().coalesce_ms)
+    }
+
+    /// Returns how to limit the lines sent to the named IRC network.
+    pub fn flood_control(network: &str) -> FloodControl {
         CONFIG
             .read()
             .unwrap()
-            .bindings
-            .iter()
-            .filter(move |b| b.irc == irc)
-            .filter(|b| b.direction != Some(Direction::Irc))
-            .map(|b| b.discord)
-            .collect()
+            .irc
+            .get(network)
+            .map(|network| network.flood.clone())
+            .unwrap_or_default()
     }
 
     /// Initializes the config to the one at the given path.
@@ -66,32 +120,40 @@ impl Config {
         Ok(())
     }
 
-    /// Returns the channels that should be connected to.
-    pub fn irc_channels() -> Vec<String> {
+    /// Returns the channels on the given network that should be connected to.
+    pub fn irc_channels(network: &str) -> Vec<String> {
         CONFIG
             .read()
             .unwrap()
             .bindings
             .iter()
-            .map(|b| b.irc.clone())
+            .flat_map(|b| &b.members)
+            .filter_map(|m| match &m.endpoint {
+                Endpoint::Irc(net, chan) if net == network => Some(chan.clone()),
+                _ => None,
+            })
             .collect()
     }
 
-    /// Returns the IRC config.
-    pub fn irc_config() -> IrcConfig {
-        CONFIG.read().unwrap().irc.clone()
+    /// Returns the config for the named IRC network, if there is one.
+    pub fn irc_network(name: &str) -> Option<IrcNetwork> {
+        CONFIG.read().unwrap().irc.get(name).cloned()
     }
 
-    /// Returns the IRC channels that should be sent messages from the named Discord channel.
-    pub fn irc_for_discord(discord: u64) -> Vec<String> {
-        CONFIG
-            .read()
-            .unwrap()
-            .bindings
+    /// Returns the names of the IRC networks.
+    pub fn irc_networks() -> Vec<String> {
+        CONFIG.read().unwrap().irc.keys().cloned().collect()
+    }
+
+    /// Returns the channels on the given network to stay in even though they aren't bound.
+    pub fn keep_channels(network: &str) -> Vec<String> {
+        let config = CONFIG.read().unwrap();
+        config
+            .keep_channels
             .iter()
-            .filter(move |b| b.discord == discord)
-            .filter(|b| b.direction != Some(Direction::Discord))
-            .map(|b| b.irc.clone())
+            .filter_map(|name| resolve_irc_name(&config.irc, name).ok())
+            .filter(|(net, _)| net == network)
+            .map(|(_, chan)| chan)
             .collect()
     }
 
@@ -100,10 +162,46 @@ impl Config {
         let mut file = File::open(path)?;
         let mut data = Vec::new();
         file.read_to_end(&mut data)?;
-        let config = toml::from_slice(&data)?;
+        let mut config: Config = toml::from_slice(&data)?;
+
+        for binding in &mut config.bindings {
+            binding.members = binding.resolve(&config.irc)?;
+        }
+        for (name, network) in &config.irc {
+            network
+                .flood
+                .check()
+                .map_err(|e| format_err!("{}: {}", name, e))?;
+            if let Some(auth) = &network.auth {
+                auth.check(&network.config)
+                    .map_err(|e| format_err!("{}: {}", name, e))?;
+            }
+        }
+        for name in &config.keep_channels {
+            resolve_irc_name(&config.irc, name)?;
+        }
+        match &config.paste {
+            Some(paste) => paste.check()?,
+            None if config.bindings.iter().any(|b| b.max_irc_lines.is_some()) => {
+                return Err(format_err!(
+                    "max_irc_lines needs a [paste] section to paste to"
+                ));
+            }
+            None => {}
+        }
         Ok(config)
     }
 
+    /// Returns the most messages to hold for a side of the relay while it is disconnected.
+    pub fn max_queued_messages() -> usize {
+        CONFIG.read().unwrap().max_queued_messages
+    }
+
+    /// Returns how long to remember which messages were relayed where.
+    pub fn message_retention() -> Duration {
+        Duration::from_secs(CONFIG.read().unwrap().message_retention_hours * 60 * 60)
+    }
+
     /// Returns a channel that will be sent `()` on config reloads.
     pub fn notify_on_reload() -> UnboundedReceiver<()> {
         let (send, recv) = unbounded();
@@ -111,6 +209,16 @@ impl Config {
         recv
     }
 
+    /// Returns the message to leave IRC channels with when their bindings are removed.
+    pub fn part_message() -> Option<String> {
+        CONFIG.read().unwrap().part_message.clone()
+    }
+
+    /// Returns where to put messages too long to relay, if anywhere.
+    pub fn paste() -> Option<PasteConfig> {
+        CONFIG.read().unwrap().paste.clone()
+    }
+
     /// Reloads the config from a file.
     pub fn reload_from(path: impl AsRef<Path>) -> Fallible<()> {
         Config::load_from(path).map(|config| {
@@ -132,21 +240,574 @@ impl Config {
             *notify_mes = new_notifies;
         })
     }
+
+    /// Returns where messages from the given channel should be relayed to, along with the binding
+    /// each route comes from. A channel that is reachable through several bindings is only
+    /// included once.
+    pub fn routes_from(from: &Endpoint) -> Vec<(Binding, Member)> {
+        let config = CONFIG.read().unwrap();
+        let mut routes: Vec<(Binding, Member)> = Vec::new();
+        for binding in &config.bindings {
+            if !binding
+                .members
+                .iter()
+                .any(|m| m.send && m.endpoint == *from)
+            {
+                continue;
+            }
+            for member in &binding.members {
+                let is_new = routes.iter().all(|(_, m)| m.endpoint != member.endpoint);
+                if member.receive && member.endpoint != *from && is_new {
+                    routes.push((binding.clone(), member.clone()));
+                }
+            }
+        }
+        routes
+    }
+
+    /// Returns the directory to keep persistent state in.
+    pub fn state_dir() -> PathBuf {
+        CONFIG.read().unwrap().state_dir.clone()
+    }
+
+    /// Returns the URLs of the webhooks used to send messages to Discord.
+    pub fn webhook_urls() -> Vec<String> {
+        CONFIG
+            .read()
+            .unwrap()
+            .bindings
+            .iter()
+            .flat_map(|b| &b.members)
+            .filter_map(|m| m.webhook.clone())
+            .collect()
+    }
+}
+
+/// Splits an IRC channel name of the form `network/#channel` into the network and the channel. The
+/// network is absent if the name has no prefix.
+pub fn split_irc_name(name: &str) -> (Option<&str>, &str) {
+    match name.find('/') {
+        Some(i) if name[i + 1..].starts_with(CHANNEL_PREFIXES) => {
+            (Some(&name[..i]), &name[i + 1..])
+        }
+        _ => (None, name),
+    }
+}
+
+/// Resolves an IRC channel name that may be prefixed with a network to the network and channel. A
+/// name without a prefix is on the only network, and is an error if there are several.
+fn resolve_irc_name(
+    networks: &BTreeMap<String, IrcNetwork>,
+    name: &str,
+) -> Fallible<(String, String)> {
+    let (network, channel) = split_irc_name(name);
+    let network = match network {
+        Some(network) if networks.contains_key(network) => network,
+        Some(network) => {
+            return Err(format_err!(
+                "{} is on an unknown network, {}",
+                name,
+                network
+            ));
+        }
+        None if networks.len() == 1 => networks.keys().next().unwrap(),
+        None if networks.is_empty() => {
+            return Err(format_err!(
+                "{} is bound, but no IRC networks are configured",
+                name
+            ));
+        }
+        None => {
+            return Err(format_err!(
+                "{} needs a network prefix, like {}/{}, since there are several networks",
+                name,
+                networks
+                    .keys()
+                    .next()
+                    .map_or(DEFAULT_NETWORK, String::as_str),
+                name
+            ))
+        }
+    };
+    Ok((network.to_string(), channel.to_string()))
+}
+
+/// Deserializes either a single IRC network or several named ones. It is taken to be several if
+/// every value in the section is a table. The section is read into a `toml::Value` first, rather
+/// than trying each form in turn, so mistakes in it are reported as they would be otherwise.
+fn deserialize_networks<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, IrcNetwork>, D::Error> {
+    let value = toml::Value::deserialize(deserializer)?;
+    let is_named = match &value {
+        toml::Value::Table(table) => table.values().all(toml::Value::is_table),
+        _ => false,
+    };
+
+    if is_named {
+        value.try_into().map_err(serde::de::Error::custom)
+    } else {
+        let network = value.try_into().map_err(serde::de::Error::custom)?;
+        let mut networks = BTreeMap::new();
+        networks.insert(DEFAULT_NETWORK.to_string(), network);
+        Ok(networks)
+    }
+}
+
+fn default_state_dir() -> PathBuf {
+    PathBuf::from("state")
+}
+
+fn default_message_retention_hours() -> u64 {
+    7 * 24
+}
+
+fn default_paste_retention_days() -> u64 {
+    30
+}
+
+fn default_max_queued_messages() -> usize {
+    100
+}
+
+fn default_burst() -> usize {
+    5
+}
+
+fn default_rate() -> f64 {
+    0.5
+}
+
+fn default_warn_depth() -> usize {
+    20
+}
+
+fn default_max_depth() -> usize {
+    50
+}
+
+fn default_coalesce_ms() -> u64 {
+    250
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// The configuration for an IRC network.
+#[derive(Clone, Deserialize)]
+pub struct IrcNetwork {
+    /// The settings for the irc crate.
+    #[serde(flatten)]
+    pub config: IrcConfig,
+
+    /// How to authenticate with the network's services.
+    pub auth: Option<IrcAuth>,
+
+    /// How to limit the lines sent to the network, so it doesn't disconnect us for flooding.
+    #[serde(default)]
+    pub flood: FloodControl,
+}
+
+/// How to limit the lines sent to an IRC network.
+#[derive(Clone, Deserialize)]
+pub struct FloodControl {
+    /// The most lines to send in a burst.
+    #[serde(default = "default_burst")]
+    pub burst: usize,
+
+    /// The lines to send each second, after a burst.
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+
+    /// The number of waiting lines at which to warn that the network can't keep up.
+    #[serde(default = "default_warn_depth")]
+    pub warn_depth: usize,
+
+    /// The number of waiting lines at which to drop or summarise further messages.
+    #[serde(default = "default_max_depth")]
+    pub max_depth: usize,
+
+    /// What to do with messages when `max_depth` lines are waiting.
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+impl FloodControl {
+    /// Checks that lines can be sent at all.
+    fn check(&self) -> Fallible<()> {
+        if self.burst == 0 {
+            Err(format_err!("The flood control burst must be at least 1"))
+        } else if self.rate.is_nan() || self.rate <= 0.0 {
+            Err(format_err!("The flood control rate must be more than 0"))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Default for FloodControl {
+    fn default() -> FloodControl {
+        FloodControl {
+            burst: default_burst(),
+            rate: default_rate(),
+            warn_depth: default_warn_depth(),
+            max_depth: default_max_depth(),
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+/// What to do with messages for IRC when too many lines are waiting to be sent.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Drop the messages.
+    Drop,
+
+    /// Replace the messages with a line saying how many were skipped, and who sent them.
+    Summarise,
+}
+
+impl Default for Overflow {
+    fn default() -> Overflow {
+        Overflow::Summarise
+    }
+}
+
+/// How to authenticate with IRC services.
+#[derive(Clone, Deserialize, PartialEq)]
+pub struct IrcAuth {
+    /// The SASL mechanism to log in with. If absent, SASL isn't used.
+    pub sasl: Option<SaslMechanism>,
+
+    /// The account to log in to. Defaults to the nickname.
+    pub account: Option<String>,
+
+    /// The account's password, for SASL PLAIN and NickServ.
+    pub password: Option<String>,
+
+    /// The path to a PKCS#12 client certificate to connect with, for SASL EXTERNAL.
+    pub client_cert: Option<String>,
+
+    /// The password for the client certificate.
+    pub client_cert_password: Option<String>,
+
+    /// Whether to identify with NickServ if SASL isn't used or fails.
+    #[serde(default)]
+    pub nickserv: bool,
+
+    /// Whether to GHOST whoever is using the nickname before identifying with NickServ.
+    #[serde(default)]
+    pub ghost: bool,
+}
+
+/// The SASL mechanisms that can be used to authenticate.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SaslMechanism {
+    /// Log in with the account name and password.
+    Plain,
+
+    /// Log in with the client certificate.
+    External,
+}
+
+impl IrcAuth {
+    /// Checks that the settings needed for the chosen ways of authenticating are present.
+    fn check(&self, config: &IrcConfig) -> Fallible<()> {
+        if config.nick_password.is_some() {
+            // The irc crate would identify with it as soon as it connects, without waiting.
+            return Err(format_err!(
+                "nick_password can't be used with [auth]; set its password instead"
+            ));
+        }
+        match self.sasl {
+            Some(SaslMechanism::Plain) if self.password.is_none() => {
+                Err(format_err!("SASL PLAIN needs a password"))
+            }
+            Some(SaslMechanism::External) if self.client_cert.is_none() => {
+                Err(format_err!("SASL EXTERNAL needs a client_cert"))
+            }
+            _ if self.nickserv && self.password.is_none() => {
+                Err(format_err!("Identifying with NickServ needs a password"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where to put messages that are too long to relay, which are replaced with a link to them. They
+/// are either written to a directory, or uploaded to a paste service.
+#[derive(Clone, Deserialize)]
+pub struct PasteConfig {
+    /// The directory to write the messages to, which should be served at `url`.
+    pub dir: Option<PathBuf>,
+
+    /// The URL the directory is served at.
+    pub url: Option<String>,
+
+    /// The address for Janus to serve the directory at itself, such as `127.0.0.1:8080`.
+    pub listen: Option<String>,
+
+    /// How long to keep messages written to the directory, in days.
+    #[serde(default = "default_paste_retention_days")]
+    pub retention_days: u64,
+
+    /// The URL of a paste service to upload the messages to instead. It is sent the text in a
+    /// POST request, and should respond with the URL of the paste.
+    pub endpoint: Option<String>,
+
+    /// The most characters to post to Discord in one go. Longer messages are pasted, while
+    /// messages are split into several posts if this is absent.
+    pub max_discord_len: Option<usize>,
+}
+
+impl PasteConfig {
+    /// Checks that the config says where to put pastes.
+    fn check(&self) -> Fallible<()> {
+        match (&self.endpoint, &self.dir, &self.url, &self.listen) {
+            (Some(_), None, None, None) => Ok(()),
+            (Some(_), _, _, _) => Err(format_err!(
+                "A paste endpoint can't be used with a paste directory"
+            )),
+            (None, Some(_), Some(_), _) => Ok(()),
+            (None, Some(_), None, _) => Err(format_err!("A paste directory needs a url")),
+            (None, None, _, _) => Err(format_err!("Pastes need either a dir or an endpoint")),
+        }
+    }
+}
+
+/// A channel on one side of the relay. IRC channel names are compared case-insensitively, as IRC
+/// servers do.
+#[derive(Clone, Debug, Eq)]
+pub enum Endpoint {
+    /// A Discord channel, by ID.
+    Discord(u64),
+
+    /// An IRC channel, by the name of its network and its own name.
+    Irc(String, String),
+}
+
+impl PartialEq for Endpoint {
+    fn eq(&self, other: &Endpoint) -> bool {
+        match (self, other) {
+            (Endpoint::Discord(a), Endpoint::Discord(b)) => a == b,
+            (Endpoint::Irc(net_a, chan_a), Endpoint::Irc(net_b, chan_b)) => {
+                net_a == net_b && chan_a.eq_ignore_ascii_case(chan_b)
+            }
+            _ => false,
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize)]
+impl Hash for Endpoint {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Endpoint::Discord(id) => {
+                0u8.hash(state);
+                id.hash(state);
+            }
+            Endpoint::Irc(network, channel) => {
+                1u8.hash(state);
+                network.hash(state);
+                channel.to_ascii_lowercase().hash(state);
+            }
+        }
+    }
+}
+
+/// A group of channels that relay messages to each other. It is either written as a Discord
+/// channel and an IRC channel, or as a list of any number of endpoints.
+#[derive(Clone, Deserialize, Serialize)]
 pub struct Binding {
-    /// The Discord channel ID.
-    pub discord: u64,
+    /// The Discord channel ID, for a binding between two channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discord: Option<u64>,
 
-    /// The IRC channel name.
-    pub irc: String,
+    /// The IRC channel name, for a binding between two channels. If there are several networks, it
+    /// must be prefixed with the network and a slash, as in `libera/#janus`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub irc: Option<String>,
 
-    /// The direction to send messages.
+    /// The direction to send messages, for a binding between two channels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub direction: Option<Direction>,
+
+    /// The URL of a Discord webhook to post IRC messages through, for a binding between two
+    /// channels. If absent, messages are sent by the bot itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+
+    /// The channels in the binding, if it isn't written as a Discord channel and an IRC channel.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<BindingEndpoint>,
+
+    /// The channels in the binding, once resolved.
+    #[serde(skip)]
+    pub members: Vec<Member>,
+
+    /// Whether to announce on IRC when a relayed Discord message is deleted.
+    #[serde(default)]
+    pub relay_deletes: bool,
+
+    /// Whether to turn the names of Discord users in IRC messages into mentions that ping them.
+    #[serde(default = "default_true")]
+    pub mentions: bool,
+
+    /// The mass mentions (`everyone` or `here`) that IRC users may use to ping Discord users.
+    #[serde(default)]
+    pub allowed_mentions: Vec<String>,
+
+    /// The IDs of the roles that IRC users may ping.
+    #[serde(default)]
+    pub allowed_roles: Vec<u64>,
+
+    /// Which name to show on IRC for Discord users.
+    #[serde(default)]
+    pub sender_name: SenderName,
+
+    /// Whether to relay IRC notices to the other channels.
+    #[serde(default)]
+    pub relay_notices: bool,
+
+    /// The IRC channel events to relay to Discord.
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+
+    /// The most lines to send to IRC for one message. Longer messages are pasted, and replaced
+    /// with their first line and a link.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_irc_lines: Option<usize>,
+}
+
+impl Binding {
+    /// Creates a binding between the two channels with the default settings.
+    pub fn new(discord: u64, irc: String) -> Binding {
+        Binding {
+            discord: Some(discord),
+            irc: Some(irc),
+            direction: None,
+            webhook: None,
+            endpoints: Vec::new(),
+            members: Vec::new(),
+            relay_deletes: false,
+            mentions: true,
+            allowed_mentions: Vec::new(),
+            allowed_roles: Vec::new(),
+            sender_name: SenderName::default(),
+            relay_notices: false,
+            events: Vec::new(),
+            max_irc_lines: None,
+        }
+    }
+
+    /// Resolves the channels in the binding, from whichever form it was written in.
+    fn resolve(&self, networks: &BTreeMap<String, IrcNetwork>) -> Fallible<Vec<Member>> {
+        match (self.discord, &self.irc) {
+            (Some(discord), Some(irc)) if self.endpoints.is_empty() => {
+                let (network, channel) = resolve_irc_name(networks, irc)?;
+                let to_discord = self.direction != Some(Direction::Irc);
+                let to_irc = self.direction != Some(Direction::Discord);
+                Ok(vec![
+                    Member {
+                        endpoint: Endpoint::Discord(discord),
+                        webhook: self.webhook.clone(),
+                        send: to_irc,
+                        receive: to_discord,
+                    },
+                    Member {
+                        endpoint: Endpoint::Irc(network, channel),
+                        webhook: None,
+                        send: to_discord,
+                        receive: to_irc,
+                    },
+                ])
+            }
+            (None, None) if self.direction.is_none() && self.webhook.is_none() => self
+                .endpoints
+                .iter()
+                .map(|endpoint| endpoint.resolve(networks))
+                .collect(),
+            _ => Err(format_err!(
+                "A binding needs either `discord` and `irc` (with optional `direction` and \
+                 `webhook`), or `endpoints`"
+            )),
+        }
+    }
+}
+
+/// One of the channels in a binding written as a list of endpoints.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BindingEndpoint {
+    /// The Discord channel ID, if this is a Discord channel.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discord: Option<u64>,
+
+    /// The IRC channel name, if this is an IRC channel. If there are several networks, it must be
+    /// prefixed with the network and a slash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub irc: Option<String>,
+
+    /// The URL of a Discord webhook to post messages to this Discord channel through.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook: Option<String>,
+
+    /// Whether messages from this channel are relayed to the others.
+    #[serde(default = "default_true")]
+    pub send: bool,
+
+    /// Whether messages from the other channels are relayed to this one.
+    #[serde(default = "default_true")]
+    pub receive: bool,
 }
 
-#[derive(Deserialize, Eq, PartialEq, Serialize)]
+impl BindingEndpoint {
+    fn resolve(&self, networks: &BTreeMap<String, IrcNetwork>) -> Fallible<Member> {
+        let endpoint = match (self.discord, &self.irc, &self.webhook) {
+            (Some(discord), None, _) => Endpoint::Discord(discord),
+            (None, Some(irc), None) => {
+                let (network, channel) = resolve_irc_name(networks, irc)?;
+                Endpoint::Irc(network, channel)
+            }
+            (None, Some(irc), Some(_)) => {
+                return Err(format_err!(
+                    "{} is an IRC channel, so can't have a webhook",
+                    irc
+                ));
+            }
+            _ => {
+                return Err(format_err!(
+                    "An endpoint needs exactly one of `discord` or `irc`"
+                ));
+            }
+        };
+        Ok(Member {
+            endpoint,
+            webhook: self.webhook.clone(),
+            send: self.send,
+            receive: self.receive,
+        })
+    }
+}
+
+/// A channel in a binding, and how it takes part.
+#[derive(Clone)]
+pub struct Member {
+    /// The channel.
+    pub endpoint: Endpoint,
+
+    /// The URL of a webhook to post to the channel through, if it is a Discord channel.
+    pub webhook: Option<String>,
+
+    /// Whether messages from this channel are relayed to the others.
+    pub send: bool,
+
+    /// Whether messages from the other channels are relayed to this one.
+    pub receive: bool,
+}
+
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub enum Direction {
     /// Send only from IRC to Discord.
@@ -155,3 +816,88 @@ pub enum Direction {
     /// Send only from Discord to IRC.
     Irc,
 }
+
+/// The kinds of IRC channel events that can be relayed to Discord.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventKind {
+    /// Users joining the channel.
+    Join,
+
+    /// Users leaving the channel.
+    Part,
+
+    /// Users disconnecting from IRC, including in netsplits.
+    Quit,
+
+    /// Users being kicked from the channel.
+    Kick,
+
+    /// Users changing their nicks.
+    Nick,
+
+    /// The topic being changed.
+    Topic,
+}
+
+/// Which of a Discord user's names to show on IRC.
+#[derive(Clone, Copy, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SenderName {
+    /// The user's nickname in the guild, falling back to their username if they don't have one.
+    /// Discord's global display names can't be used as a fallback, since serenity 0.5 predates
+    /// them and doesn't expose them.
+    Nickname,
+
+    /// The user's username.
+    Username,
+}
+
+impl Default for SenderName {
+    fn default() -> SenderName {
+        SenderName::Nickname
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(&format!("bindings = []\n{}", config))
+    }
+
+    #[test]
+    fn single_and_named_networks_are_read() {
+        let single = parse("[irc]\nnickname = \"janus\"\nserver = \"irc.example\"").unwrap();
+        assert_eq!(single.irc.keys().collect::<Vec<_>>(), vec![DEFAULT_NETWORK]);
+
+        let named =
+            parse("[irc.a]\nnickname = \"janus\"\n[irc.b]\nnickname = \"janus\"\nport = 6697")
+                .unwrap();
+        assert_eq!(named.irc.keys().collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(named.irc["b"].config.port, Some(6697));
+    }
+
+    #[test]
+    fn auth_settings_are_checked() {
+        let auth = |extra: &str| {
+            let config = parse(&format!("[irc]\nnickname = \"janus\"\n{}", extra)).unwrap();
+            let network = &config.irc[DEFAULT_NETWORK];
+            network.auth.as_ref().unwrap().check(&network.config)
+        };
+        assert!(auth("[irc.auth]\nsasl = \"plain\"\npassword = \"hunter2\"").is_ok());
+        assert!(auth("[irc.auth]\nsasl = \"plain\"").is_err());
+        assert!(auth("[irc.auth]\nsasl = \"external\"").is_err());
+        assert!(auth("[irc.auth]\nnickserv = true").is_err());
+        assert!(auth("nick_password = \"hunter2\"\n[irc.auth]\nnickserv = true").is_err());
+    }
+
+    #[test]
+    fn mistakes_in_networks_are_reported() {
+        for config in &["[irc]\nport = \"6697\"", "[irc.a]\nport = \"6697\""] {
+            let err = parse(config).err().unwrap().to_string();
+            assert!(err.contains("6697"), "{}", err);
+        }
+    }
+}
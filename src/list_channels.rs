@@ -1,7 +1,7 @@
 use crate::config::Config;
 use failure::{format_err, Error, SyncFailure};
 use futures::{
-    future::{err, Either, Future},
+    future::{err, join_all, Either, Future},
     sync::oneshot::{channel, Sender},
     Stream,
 };
@@ -35,13 +35,35 @@ pub struct Channels {
 }
 
 pub fn run(discord_token: &str) -> impl Future<Item = Channels, Error = Error> {
-    get_irc_channels()
-        .join(get_discord_channels(discord_token))
+    let networks = Config::irc_networks();
+    // Channel names only need their network to tell them apart if there are several.
+    let qualify = networks.len() > 1;
+    let irc = join_all(networks.into_iter().map(move |network| {
+        get_irc_channels(&network).map(move |chans| {
+            chans
+                .into_iter()
+                .map(|chan| {
+                    if qualify {
+                        format!("{}/{}", network, chan)
+                    } else {
+                        chan
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+    }))
+    .map(|lists| lists.into_iter().flatten().collect());
+
+    irc.join(get_discord_channels(discord_token))
         .map(|(irc, discord)| Channels { irc, discord })
 }
 
-fn get_irc_channels() -> impl Future<Item = Vec<String>, Error = Error> {
-    match IrcClient::from_config(Config::irc_config()) {
+fn get_irc_channels(network: &str) -> impl Future<Item = Vec<String>, Error = Error> {
+    let config = match Config::irc_network(network) {
+        Some(network) => network.config,
+        None => return Either::B(err(format_err!("Unknown IRC network {}", network))),
+    };
+    match IrcClient::from_config(config) {
         Ok(client) => {
             if let Err(e) = client.identify() {
                 return Either::B(err(Error::from(e)));
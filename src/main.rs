@@ -1,14 +1,15 @@
 #[macro_use]
 extern crate serde_derive;
 
+mod check;
 mod config;
 mod list_channels;
 mod server;
 
-use failure::{Error, Fallible};
+use failure::{format_err, Error, Fallible};
 use futures::future::{Either, Future};
 use log::error;
-use std::{collections::HashSet, path::PathBuf};
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 fn main() {
@@ -22,35 +23,33 @@ fn main() {
 
 fn run(opts: Options) -> Fallible<()> {
     opts.start_logger()?;
-    config::Config::init(opts.config_file)?;
+    if let Subcommand::Check = opts.subcommand {
+        return check::run(&opts.config_file);
+    }
+    config::Config::init(opts.config_file.clone())?;
 
     let fut = match opts.subcommand {
         Subcommand::ListChannels { as_bindings } => Either::A(
-            list_channels::run(&opts.discord_token).and_then(move |chans| {
+            list_channels::run(opts.discord_token()?).and_then(move |chans| {
                 if as_bindings {
-                    use crate::config::Binding;
+                    use crate::config::{split_irc_name, Binding};
 
                     #[derive(Serialize)]
                     struct Wrapper {
                         bindings: Vec<Binding>,
                     }
 
-                    let irc_channels = chans.irc.into_iter().collect::<HashSet<String>>();
+                    let irc_channels = &chans.irc;
                     let bindings = chans
                         .discord
                         .values()
                         .flatten()
-                        .filter_map(|&(ref name, id)| {
+                        .flat_map(|&(ref name, id)| {
                             let irc_name = format!("#{}", name);
-                            if irc_channels.contains(&irc_name) {
-                                Some(Binding {
-                                    irc: irc_name,
-                                    discord: id,
-                                    direction: None,
-                                })
-                            } else {
-                                None
-                            }
+                            irc_channels
+                                .iter()
+                                .filter(move |chan| split_irc_name(chan).1 == irc_name)
+                                .map(move |chan| Binding::new(id, chan.clone()))
                         })
                         .collect();
                     let bindings = toml::to_string_pretty(&Wrapper { bindings })?;
@@ -61,7 +60,8 @@ fn run(opts: Options) -> Fallible<()> {
                 }
             }),
         ),
-        Subcommand::Run => Either::B(server::run(&opts.discord_token)),
+        Subcommand::Run => Either::B(server::run(opts.discord_token()?)),
+        Subcommand::Check => unreachable!(),
     };
     // TODO: Use a real runtime.
     fut.wait()
@@ -80,9 +80,9 @@ struct Options {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbose: usize,
 
-    /// The Discord bot token.
+    /// The Discord bot token. Required for all subcommands but `check`.
     #[structopt(env = "DISCORD_TOKEN")]
-    pub discord_token: String,
+    pub discord_token: Option<String>,
 
     /// The syslog server to send logs to.
     #[structopt(short = "s", long = "syslog-server", env = "SYSLOG_SERVER")]
@@ -104,6 +104,14 @@ struct Options {
 }
 
 impl Options {
+    /// Returns the Discord bot token, or an error if it wasn't given.
+    fn discord_token(&self) -> Fallible<&str> {
+        self.discord_token
+            .as_ref()
+            .map(String::as_str)
+            .ok_or_else(|| format_err!("A Discord bot token is required"))
+    }
+
     /// Sets up logging as specified by the `-q`, `-s`, and `-v` flags.
     fn start_logger(&self) -> Fallible<()> {
         use fern::Dispatch;
@@ -171,6 +179,10 @@ enum Subcommand {
     /// Starts Janus.
     #[structopt(name = "run")]
     Run,
+
+    /// Checks the config file for mistakes, and prints where messages will be relayed.
+    #[structopt(name = "check")]
+    Check,
 }
 
 /// Logs an error, including its causes and backtrace (if possible).
@@ -0,0 +1,46 @@
+//! Exponential backoff with jitter, for reconnecting after failures.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// The delay before the first retry.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest delay between retries.
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How long a connection has to stay up before a drop is treated as a new failure, rather than
+/// a continuation of the last one.
+pub const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Tracks how long to wait before the next retry.
+pub struct Backoff {
+    delay: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff {
+            delay: INITIAL_DELAY,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay before the next retry, and doubles the delay for the one after, up to the
+    /// maximum. The returned delay is randomly adjusted by up to a quarter in either direction, so
+    /// that retries don't happen in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (self.delay * 2).min(MAX_DELAY);
+
+        let millis = delay.as_secs() * 1000 + u64::from(delay.subsec_millis());
+        let jitter = rand::thread_rng().gen_range(0, millis / 2 + 1);
+        Duration::from_millis(millis - millis / 4 + jitter)
+    }
+
+    /// Resets the delay back to the initial delay, after a success.
+    pub fn reset(&mut self) {
+        self.delay = INITIAL_DELAY;
+    }
+}
@@ -0,0 +1,397 @@
+//! Turns IRC membership and topic changes into channel events, batching bursts of them (such as
+//! netsplits) together.
+
+use futures::sync::mpsc::UnboundedSender;
+use irc::proto::{command::Command, response::Response, Message};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use crate::server::message::{ChannelEvent, ChannelEventKind, Endpoint, RelayEvent};
+
+lazy_static! {
+    static ref NETSPLIT_PATTERN: Regex = Regex::new(r"^[\w.-]+\.[\w-]+ [\w.-]+\.[\w-]+$").unwrap();
+}
+
+/// How long to wait for more events before sending a batch.
+const BATCH_WINDOW: Duration = Duration::from_secs(2);
+
+/// The users in each channel the bot is in on a network, by lowercased nick. The irc crate tracks
+/// this too, but it forgets users before their quits are seen, so the channels a quit applies to
+/// can't be found from it.
+pub struct Members(String, HashMap<String, HashSet<String>>);
+
+impl Members {
+    /// Creates an empty tracker for the named network.
+    pub fn new(network: String) -> Members {
+        Members(network, HashMap::new())
+    }
+
+    /// Updates the members for a message, returning the channel events it causes. Events caused by
+    /// the bot itself are not returned.
+    pub fn handle(&mut self, own_nick: &str, msg: &Message) -> Vec<ChannelEvent> {
+        let sender = msg.source_nickname().unwrap_or("");
+        let is_own = sender.eq_ignore_ascii_case(own_nick);
+        let network = self.0.clone();
+        let event = |chan: &str, nick: &str, kind| ChannelEvent {
+            origin: Endpoint::Irc(network.clone(), chan.to_string()),
+            nicks: vec![nick.to_string()],
+            kind,
+        };
+
+        match &msg.command {
+            Command::JOIN(chans, _, _) => chans
+                .split(',')
+                .filter_map(|chan| {
+                    if is_own {
+                        self.forget_channel(chan);
+                        self.1.insert(chan.to_string(), HashSet::new());
+                        None
+                    } else {
+                        self.add(chan, sender);
+                        Some(event(chan, sender, ChannelEventKind::Join))
+                    }
+                })
+                .collect(),
+            Command::PART(chans, reason) => chans
+                .split(',')
+                .filter_map(|chan| {
+                    if is_own {
+                        self.forget_channel(chan);
+                        None
+                    } else {
+                        self.remove(chan, sender);
+                        Some(event(chan, sender, ChannelEventKind::Part(reason.clone())))
+                    }
+                })
+                .collect(),
+            Command::KICK(chans, nick, reason) => chans
+                .split(',')
+                .filter_map(|chan| {
+                    if nick.eq_ignore_ascii_case(own_nick) {
+                        self.forget_channel(chan);
+                        None
+                    } else {
+                        self.remove(chan, nick);
+                        let kind = ChannelEventKind::Kick(sender.to_string(), reason.clone());
+                        Some(event(chan, nick, kind))
+                    }
+                })
+                .collect(),
+            Command::QUIT(reason) if !is_own => {
+                let kind = match reason {
+                    Some(reason) if NETSPLIT_PATTERN.is_match(reason) => {
+                        ChannelEventKind::Netsplit(reason.clone())
+                    }
+                    _ => ChannelEventKind::Quit(reason.clone()),
+                };
+                self.channels_of(sender)
+                    .into_iter()
+                    .map(|chan| {
+                        self.remove(&chan, sender);
+                        event(&chan, sender, kind.clone())
+                    })
+                    .collect()
+            }
+            Command::NICK(new) if !is_own => self
+                .channels_of(sender)
+                .into_iter()
+                .map(|chan| {
+                    self.remove(&chan, sender);
+                    self.add(&chan, new);
+                    event(&chan, sender, ChannelEventKind::Nick(new.clone()))
+                })
+                .collect(),
+            Command::TOPIC(chan, Some(topic)) if !is_own && !sender.is_empty() => {
+                vec![event(chan, sender, ChannelEventKind::Topic(topic.clone()))]
+            }
+            Command::Response(Response::RPL_NAMREPLY, args, Some(names)) => {
+                if let Some(chan) = args.get(2) {
+                    for name in names.split_whitespace() {
+                        self.add(chan, name.trim_start_matches(|c| "~&@%+".contains(c)));
+                    }
+                }
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn add(&mut self, chan: &str, nick: &str) {
+        if let Some(members) = self.members_mut(chan) {
+            members.insert(nick.to_lowercase());
+        }
+    }
+
+    fn remove(&mut self, chan: &str, nick: &str) {
+        if let Some(members) = self.members_mut(chan) {
+            members.remove(&nick.to_lowercase());
+        }
+    }
+
+    fn forget_channel(&mut self, chan: &str) {
+        self.1.retain(|name, _| !name.eq_ignore_ascii_case(chan));
+    }
+
+    fn members_mut(&mut self, chan: &str) -> Option<&mut HashSet<String>> {
+        self.1
+            .iter_mut()
+            .find(|(name, _)| name.eq_ignore_ascii_case(chan))
+            .map(|(_, members)| members)
+    }
+
+    fn channels_of(&self, nick: &str) -> Vec<String> {
+        let nick = nick.to_lowercase();
+        self.1
+            .iter()
+            .filter(|(_, members)| members.contains(&nick))
+            .map(|(chan, _)| chan.clone())
+            .collect()
+    }
+}
+
+/// Collects channel events into batches, sending each batch once no more events have arrived for
+/// a short while. Events of the same kind in the same channel are merged. This blocks, so it
+/// should be run on its own thread.
+pub fn batch_events(recv: Receiver<ChannelEvent>, send: UnboundedSender<RelayEvent>) {
+    while let Ok(first) = recv.recv() {
+        let mut batch = vec![first];
+        let mut disconnected = false;
+        let deadline = Instant::now() + BATCH_WINDOW;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            match recv.recv_timeout(deadline - now) {
+                Ok(event) => merge(&mut batch, event),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    disconnected = true;
+                    break;
+                }
+            }
+        }
+
+        for event in batch {
+            if send.unbounded_send(RelayEvent::Channel(event)).is_err() {
+                return;
+            }
+        }
+        if disconnected {
+            return;
+        }
+    }
+}
+
+/// Adds an event to a batch, merging it with an existing event if possible.
+fn merge(batch: &mut Vec<ChannelEvent>, event: ChannelEvent) {
+    let existing = batch
+        .iter_mut()
+        .find(|e| e.origin == event.origin && e.kind == event.kind);
+    match existing {
+        Some(existing) => {
+            for nick in event.nicks {
+                if !existing.nicks.contains(&nick) {
+                    existing.nicks.push(nick);
+                }
+            }
+        }
+        None => batch.push(event),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(line: &str) -> Message {
+        format!("{}\r\n", line).parse().unwrap()
+    }
+
+    /// Feeds lines to the tracker, returning the channels, nicks and kinds of the events caused.
+    fn handle(
+        members: &mut Members,
+        lines: &[&str],
+    ) -> Vec<(String, Vec<String>, ChannelEventKind)> {
+        lines
+            .iter()
+            .flat_map(|line| members.handle("janus", &msg(line)))
+            .map(|event| match event.origin {
+                Endpoint::Irc(_, chan) => (chan, event.nicks, event.kind),
+                Endpoint::Discord(_) => panic!("IRC event from Discord"),
+            })
+            .collect()
+    }
+
+    fn event(
+        chan: &str,
+        nick: &str,
+        kind: ChannelEventKind,
+    ) -> (String, Vec<String>, ChannelEventKind) {
+        (chan.to_string(), vec![nick.to_string()], kind)
+    }
+
+    /// Returns a tracker that has joined `#a` and `#b`, with `alice` in both and `bob` in `#a`.
+    fn joined() -> Members {
+        let mut members = Members::new("net".to_string());
+        handle(
+            &mut members,
+            &[
+                ":janus!j@host JOIN #a",
+                ":janus!j@host JOIN #b",
+                ":irc.example.net 353 janus = #a :@Alice +bob",
+                ":irc.example.net 353 janus = #b :~alice",
+            ],
+        );
+        members
+    }
+
+    #[test]
+    fn own_membership_changes_are_not_events() {
+        let mut members = Members::new("net".to_string());
+        assert_eq!(handle(&mut members, &[":janus!j@host JOIN #a"]), vec![]);
+        assert_eq!(handle(&mut members, &[":janus!j@host PART #a"]), vec![]);
+        let mut members = joined();
+        assert_eq!(
+            handle(&mut members, &[":op!o@host KICK #a JANUS :bye"]),
+            vec![]
+        );
+        assert_eq!(
+            handle(&mut members, &[":janus!j@host QUIT :restarting"]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn joins_parts_and_kicks_are_events() {
+        let mut members = joined();
+        assert_eq!(
+            handle(
+                &mut members,
+                &[
+                    ":carol!c@host JOIN #a",
+                    ":carol!c@host PART #a :later",
+                    ":op!o@host KICK #a bob :spam",
+                ]
+            ),
+            vec![
+                event("#a", "carol", ChannelEventKind::Join),
+                event(
+                    "#a",
+                    "carol",
+                    ChannelEventKind::Part(Some("later".to_string()))
+                ),
+                event(
+                    "#a",
+                    "bob",
+                    ChannelEventKind::Kick("op".to_string(), Some("spam".to_string()))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn quits_apply_to_every_shared_channel() {
+        let mut members = joined();
+        let mut events = handle(&mut members, &[":alice!a@host QUIT :bye"]);
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        let quit = ChannelEventKind::Quit(Some("bye".to_string()));
+        assert_eq!(
+            events,
+            vec![
+                event("#a", "alice", quit.clone()),
+                event("#b", "alice", quit)
+            ]
+        );
+        assert_eq!(handle(&mut members, &[":alice!a@host QUIT :again"]), vec![]);
+    }
+
+    #[test]
+    fn quits_from_netsplits_are_recognised() {
+        let mut members = joined();
+        assert_eq!(
+            handle(&mut members, &[":bob!b@host QUIT :irc.a.net irc.b.net"]),
+            vec![event(
+                "#a",
+                "bob",
+                ChannelEventKind::Netsplit("irc.a.net irc.b.net".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn nick_changes_are_followed() {
+        let mut members = joined();
+        assert_eq!(
+            handle(&mut members, &[":bob!b@host NICK bobby"]),
+            vec![event(
+                "#a",
+                "bob",
+                ChannelEventKind::Nick("bobby".to_string())
+            )]
+        );
+        assert_eq!(
+            handle(&mut members, &[":bobby!b@host QUIT"]),
+            vec![event("#a", "bobby", ChannelEventKind::Quit(None))]
+        );
+        assert_eq!(handle(&mut members, &[":bob!b@host QUIT"]), vec![]);
+    }
+
+    #[test]
+    fn names_prefixes_are_stripped() {
+        let mut members = joined();
+        assert_eq!(handle(&mut members, &[":alice!a@host PART #b"]).len(), 1);
+        assert_eq!(
+            handle(&mut members, &[":alice!a@host QUIT"]),
+            vec![event("#a", "alice", ChannelEventKind::Quit(None))]
+        );
+    }
+
+    #[test]
+    fn merges_events_of_the_same_kind_and_channel() {
+        let origin = |chan: &str| Endpoint::Irc("net".to_string(), chan.to_string());
+        let event = |chan: &str, nick: &str, kind| ChannelEvent {
+            origin: origin(chan),
+            nicks: vec![nick.to_string()],
+            kind,
+        };
+        let mut batch = vec![event("#a", "alice", ChannelEventKind::Join)];
+        merge(&mut batch, event("#a", "bob", ChannelEventKind::Join));
+        merge(&mut batch, event("#a", "alice", ChannelEventKind::Join));
+        merge(&mut batch, event("#b", "carol", ChannelEventKind::Join));
+        merge(
+            &mut batch,
+            event("#a", "dave", ChannelEventKind::Quit(None)),
+        );
+
+        let batch = batch
+            .into_iter()
+            .map(|e| (e.origin, e.nicks, e.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            batch,
+            vec![
+                (
+                    origin("#a"),
+                    vec!["alice".to_string(), "bob".to_string()],
+                    ChannelEventKind::Join
+                ),
+                (
+                    origin("#b"),
+                    vec!["carol".to_string()],
+                    ChannelEventKind::Join
+                ),
+                (
+                    origin("#a"),
+                    vec!["dave".to_string()],
+                    ChannelEventKind::Quit(None)
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//! A log of messages that couldn't be posted to Discord, kept as JSON lines in the state directory
+//! so they can be read or reposted by hand rather than being lost.
+
+use failure::{Error, Fallible};
+use log::error;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::Config, log_err};
+
+/// The name of the file dead letters are appended to.
+const DEAD_LETTER_FILE: &str = "dead-letters.jsonl";
+
+/// A message that couldn't be posted.
+#[derive(Serialize)]
+struct DeadLetter<'a> {
+    /// The ID of the Discord channel it was for.
+    channel: u64,
+
+    /// The name of the IRC user who sent it, if it was relayed from IRC.
+    sender: Option<&'a str>,
+
+    /// The text that would have been posted.
+    content: &'a str,
+
+    /// Why posting it failed.
+    error: String,
+
+    /// When it was given up on, in seconds since the Unix epoch.
+    timestamp: u64,
+}
+
+/// Records a message that couldn't be posted to a Discord channel. Errors writing the log are
+/// logged themselves, since there's nowhere else to put the message.
+pub fn record(channel: u64, sender: Option<&str>, content: &str, why: &Error) {
+    error!(
+        "Gave up posting to Discord channel {}, see {}: {}",
+        channel, DEAD_LETTER_FILE, why
+    );
+    let letter = DeadLetter {
+        channel,
+        sender,
+        content,
+        error: why.to_string(),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+    if let Err(err) = append(&letter) {
+        log_err(err);
+    }
+}
+
+/// Appends a dead letter to the file.
+fn append(letter: &DeadLetter) -> Fallible<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(Config::state_dir().join(DEAD_LETTER_FILE))?;
+    let mut line = serde_json::to_vec(letter)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
@@ -1,16 +1,31 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
 use itertools::Itertools;
 use lazy_static::lazy_static;
-use regex::Regex;
-use serenity::model::channel::Message;
+use regex::{Captures, Regex};
+use serenity::{
+    model::{
+        channel::Message,
+        id::{ChannelId, RoleId},
+        user::User,
+    },
+    CACHE,
+};
 
 use crate::server::discord_side::ID_TO_NICK;
 
 lazy_static! {
     static ref MENTION_PATTERN: Regex = Regex::new(r"<@!?(?P<id>[0-9]+)>").unwrap();
+    static ref REFERENCE_PATTERN: Regex = Regex::new(concat!(
+        r"<#(?P<channel>[0-9]+)>",
+        r"|<@&(?P<role>[0-9]+)>",
+        r"|<a?:(?P<emoji>\w+):[0-9]+>",
+        r"|<t:(?P<time>-?[0-9]+)(?::(?P<style>[tTdDfFR]))?>",
+    ))
+    .unwrap();
 }
 
-fn find_nickname(id: &u64, message: &Message) -> Option<String> {
-    for user in &message.mentions {
+fn find_nickname(id: &u64, mentions: &[User]) -> Option<String> {
+    for user in mentions {
         if *user.id.as_u64() == *id {
             let mut map = ID_TO_NICK.write();
             map.insert(*id, user.name.clone());
@@ -21,17 +36,98 @@ fn find_nickname(id: &u64, message: &Message) -> Option<String> {
 }
 
 pub fn get_content(message: &Message) -> String {
-    let other_content = MENTION_PATTERN.split(&message.content).map(str::to_owned);
+    get_content_with_mentions(&message.content, &message.mentions)
+}
+
+/// Resolves the mentions and other references in the content of a message, given the users it
+/// mentions.
+pub fn get_content_with_mentions(content: &str, mentioned: &[User]) -> String {
+    let other_content = MENTION_PATTERN.split(content).map(str::to_owned);
     let mentions = MENTION_PATTERN
-        .captures_iter(&message.content)
+        .captures_iter(content)
         .map(|captures| captures["id"].parse::<u64>().unwrap())
         .map(|id| {
             ID_TO_NICK
                 .read()
                 .get(&id)
                 .map(String::clone)
-                .or_else(|| find_nickname(&id, message))
+                .or_else(|| find_nickname(&id, mentioned))
                 .unwrap_or_else(|| format!("<@{}>", id))
         });
-    other_content.interleave(mentions).join("")
+    resolve_references(&other_content.interleave(mentions).join(""))
+}
+
+/// Renders channel links, role mentions, custom emoji and timestamps readably, looking up names in
+/// the cache.
+fn resolve_references(content: &str) -> String {
+    REFERENCE_PATTERN
+        .replace_all(content, |captures: &Captures| {
+            let id = |name: &str| captures[name].parse::<u64>().unwrap_or(0);
+            if captures.name("channel").is_some() {
+                let name = CACHE
+                    .read()
+                    .guild_channel(ChannelId(id("channel")))
+                    .map(|chan| chan.read().name.clone())
+                    .unwrap_or_else(|| "deleted-channel".to_string());
+                format!("#{}", name)
+            } else if captures.name("role").is_some() {
+                let role = RoleId(id("role"));
+                let name = CACHE
+                    .read()
+                    .guilds
+                    .values()
+                    .find_map(|guild| guild.read().roles.get(&role).map(|r| r.name.clone()))
+                    .unwrap_or_else(|| "deleted-role".to_string());
+                format!("@{}", name)
+            } else if let Some(emoji) = captures.name("emoji") {
+                format!(":{}:", emoji.as_str())
+            } else {
+                let style = captures.name("style").map_or("f", |style| style.as_str());
+                captures["time"]
+                    .parse()
+                    .ok()
+                    .and_then(|secs| format_timestamp(secs, style))
+                    .unwrap_or_else(|| captures[0].to_string())
+            }
+        })
+        .into_owned()
+}
+
+/// Formats a timestamp the way the Discord client would for the given style, in UTC.
+fn format_timestamp(secs: i64, style: &str) -> Option<String> {
+    let time = DateTime::<Utc>::from_utc(NaiveDateTime::from_timestamp_opt(secs, 0)?, Utc);
+    let format = match style {
+        "t" => "%H:%M UTC",
+        "T" => "%H:%M:%S UTC",
+        "d" => "%Y-%m-%d",
+        "D" => "%-d %B %Y",
+        "F" => "%A, %-d %B %Y %H:%M UTC",
+        "R" => return Some(format_relative(secs - Utc::now().timestamp())),
+        _ => "%-d %B %Y %H:%M UTC",
+    };
+    Some(time.format(format).to_string())
+}
+
+/// Formats an offset from the current time, in seconds, like "in 3 hours" or "2 days ago".
+fn format_relative(offset: i64) -> String {
+    const UNITS: &[(i64, &str)] = &[
+        (365 * 24 * 60 * 60, "year"),
+        (30 * 24 * 60 * 60, "month"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+        (1, "second"),
+    ];
+    let (size, unit) = UNITS
+        .iter()
+        .cloned()
+        .find(|&(size, _)| offset.abs() >= size)
+        .unwrap_or((1, "second"));
+    let n = offset.abs() / size;
+    let plural = if n == 1 { "" } else { "s" };
+    if offset < 0 {
+        format!("{} {}{} ago", n, unit, plural)
+    } else {
+        format!("in {} {}{}", n, unit, plural)
+    }
 }
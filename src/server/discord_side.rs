@@ -1,105 +1,710 @@
-use std::collections::HashMap;
-use std::{iter::once, sync::Arc, thread::spawn};
+use std::collections::{HashMap, VecDeque};
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
 
 use antidote::RwLock;
 use failure::{format_err, Error, SyncFailure};
 use futures::{
-    future::{err, Either, Future},
+    future::{err, Future},
     sync::{
         mpsc::{UnboundedReceiver, UnboundedSender},
         oneshot::channel,
     },
     Stream,
 };
+use hyper::{client::Response as HyperResponse, status::StatusCode};
 use lazy_static::lazy_static;
-use log::error;
+use log::{error, info, warn};
 use serenity::{
+    builder::ExecuteWebhook,
     client::{Client, Context, EventHandler},
+    http::{self, HttpError},
     model::{
         channel::Message,
+        event::MessageUpdateEvent,
         gateway::Ready,
-        id::{ChannelId, UserId},
+        id::{ChannelId, MessageId, UserId},
         prelude::Member,
     },
+    CACHE,
 };
 
-use crate::server::discord_parser::get_content;
+use crate::{
+    config::Config,
+    server::{
+        backoff::{Backoff, STABLE_CONNECTION},
+        dead_letters,
+        discord_parser::{get_content, get_content_with_mentions},
+        discord_split::{split_message, MAX_MESSAGE_LEN},
+        formatting::escape_markdown,
+        irc_parser::ZERO_WIDTH_SPACE,
+        message::{Endpoint, MessageKind, RelayEdit, RelayEvent, RelayMessage},
+        paste,
+        store::{Entry, MessageStore},
+    },
+};
 
 lazy_static! {
     pub static ref ID_TO_NICK: Arc<RwLock<HashMap<u64, String>>> =
         Arc::new(RwLock::new(HashMap::new()));
 }
 
-/// Starts listening for Discord messages, communicating over the given channels.
+/// The most times to try posting a message before giving up on it.
+const MAX_POST_ATTEMPTS: usize = 5;
+
+/// A message to be posted to Discord.
+pub struct Outgoing {
+    /// The ID of the channel to post to.
+    pub channel: u64,
+
+    /// The URL of the webhook to post through. If absent, the message is sent by the bot.
+    pub webhook: Option<String>,
+
+    /// The text to post.
+    pub content: Arc<String>,
+
+    /// The IRC message being relayed, if the text corresponds to one.
+    pub source: Option<Arc<RelayMessage>>,
+
+    /// The name to show for the sender of the source message, as the binding asks for it.
+    pub sender: Option<String>,
+}
+
+/// The state of the Discord client, as reported in the logs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// The client is connecting to the gateway.
+    Connecting,
+
+    /// The client is connected and ready.
+    Connected,
+
+    /// The client failed, and will be restarted after a delay.
+    Restarting,
+}
+
+/// The state of the Discord client and the messages waiting for it to connect. These share a lock
+/// so a message can't be queued just after the queue is posted and left there until the next
+/// reconnect.
+struct Link {
+    state: State,
+
+    /// The messages waiting to be posted once the client is back.
+    queue: VecDeque<Outgoing>,
+}
+
+/// Keeps track of the Discord client, holding messages for Discord while it is down.
+struct Supervisor {
+    link: Mutex<Link>,
+
+    /// The queues of the threads posting to each channel, by channel ID.
+    channels: Mutex<HashMap<u64, mpsc::Sender<Outgoing>>>,
+}
+
+impl Supervisor {
+    fn new() -> Supervisor {
+        Supervisor {
+            link: Mutex::new(Link {
+                state: State::Connecting,
+                queue: VecDeque::new(),
+            }),
+            channels: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a change in the client's state, logging it.
+    fn set_state(&self, new: State) {
+        change_state(&mut self.link.lock().unwrap().state, new);
+    }
+
+    /// Queues a message to be posted after the others for its channel if the client is
+    /// connected, or queues it until it is if not.
+    fn send(&self, msg: Outgoing) {
+        {
+            let mut link = self.link.lock().unwrap();
+            if link.state != State::Connected {
+                return enqueue(&mut link.queue, msg);
+            }
+        }
+        self.post(msg);
+    }
+
+    /// Queues a message to be posted after the others for its channel.
+    fn post(&self, msg: Outgoing) {
+        let mut channels = self.channels.lock().unwrap();
+        let queue = channels.entry(msg.channel).or_insert_with(|| {
+            let (send, recv) = mpsc::channel();
+            spawn(move || post_in_order(recv));
+            send
+        });
+        if let Err(mpsc::SendError(msg)) = queue.send(msg) {
+            // The thread for the channel panicked, so start another.
+            error!("Restarting the queue for Discord channel {}", msg.channel);
+            channels.remove(&msg.channel);
+            drop(channels);
+            self.post(msg);
+        }
+    }
+
+    /// Posts any queued messages, and marks the client as connected. The queued messages are
+    /// handed to their channels' threads before any message that arrives afterwards, so each
+    /// channel still gets its messages in order.
+    fn connected(&self) {
+        let mut link = self.link.lock().unwrap();
+        if !link.queue.is_empty() {
+            info!(
+                "Posting {} messages queued while Discord was down",
+                link.queue.len()
+            );
+        }
+        for msg in link.queue.drain(..) {
+            self.post(msg);
+        }
+        change_state(&mut link.state, State::Connected);
+    }
+}
+
+/// Records a change in the client's state, logging it.
+fn change_state(state: &mut State, new: State) {
+    if *state != new {
+        info!("Discord client: {:?} -> {:?}", *state, new);
+        *state = new;
+    }
+}
+
+/// Adds a message to the queue, dropping the oldest message if it is full.
+fn enqueue(queue: &mut VecDeque<Outgoing>, msg: Outgoing) {
+    if queue.len() >= Config::max_queued_messages() {
+        warn!("Discord is disconnected and the queue is full, dropping a message");
+        queue.pop_front();
+    }
+    queue.push_back(msg);
+}
+
+/// Starts listening for Discord messages, communicating over the given channels. The client is
+/// restarted if it fails, and messages from IRC are queued until it is back.
 pub fn start_discord(
     discord_token: &str,
-    discord_send: UnboundedSender<(u64, String, String)>,
-    discord_recv: UnboundedReceiver<(u64, Arc<String>)>,
+    discord_send: UnboundedSender<RelayEvent>,
+    discord_recv: UnboundedReceiver<Outgoing>,
 ) -> impl Future<Item = (), Error = Error> {
-    match Client::new(discord_token, Handler(discord_send, RwLock::new(UserId(0)))) {
-        Ok(mut discord) => {
-            let (end_send, end_recv) = channel();
-            spawn(move || {
-                let err = loop {
-                    match discord.start() {
-                        Ok(()) => {}
-                        Err(e) => break Error::from(SyncFailure::new(e)),
-                    }
+    let supervisor = Arc::new(Supervisor::new());
+
+    let (end_send, end_recv) = channel();
+    let token = discord_token.to_string();
+    let thread_supervisor = supervisor.clone();
+    spawn(move || {
+        let err = supervise(&token, &thread_supervisor, discord_send);
+        let _ = end_send.send(err);
+    });
+
+    let (coalesce_send, coalesce_recv) = mpsc::channel();
+    spawn(move || coalesce(&coalesce_recv, &supervisor));
+
+    let end = end_recv
+        .map_err(|_| format_err!("Discord client thread panicked!"))
+        .and_then(err);
+    discord_recv
+        .map_err(|()| unreachable!())
+        .for_each(move |msg| {
+            coalesce_send
+                .send(msg)
+                .map_err(|_| format_err!("Discord message coalescer stopped"))
+        })
+        .select(end)
+        .map(|((), _)| ())
+        .map_err(|(e, _)| e)
+}
+
+/// Posts messages once no more lines from the same IRC user have arrived for the coalescing
+/// window, merging consecutive lines into one message. Each channel and webhook has its own
+/// pending message, so lines relayed to several channels, or from several busy IRC channels, are
+/// still merged.
+fn coalesce(recv: &mpsc::Receiver<Outgoing>, supervisor: &Supervisor) {
+    let mut pending: HashMap<(u64, Option<String>), (Outgoing, Instant)> = HashMap::new();
+    loop {
+        let next = match pending.values().map(|(_, deadline)| *deadline).min() {
+            Some(deadline) => recv.recv_timeout(deadline.saturating_duration_since(Instant::now())),
+            None => recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match next {
+            Ok(msg) => {
+                let window = Config::coalesce_window();
+                let key = (msg.channel, msg.webhook.clone());
+                let msg = match pending.remove(&key) {
+                    Some((prev, _)) => match merge(prev, msg) {
+                        Ok(merged) => merged,
+                        Err((prev, msg)) => {
+                            supervisor.send(prev);
+                            msg
+                        }
+                    },
+                    None => msg,
                 };
-                let _ = end_send.send(err);
+                if window == Duration::from_secs(0) {
+                    supervisor.send(msg);
+                } else {
+                    pending.insert(key, (msg, Instant::now() + window));
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let due = pending
+                    .iter()
+                    .filter(|(_, (_, deadline))| *deadline <= now)
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                for key in due {
+                    if let Some((msg, _)) = pending.remove(&key) {
+                        supervisor.send(msg);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                for (_, (msg, _)) in pending.drain() {
+                    supervisor.send(msg);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Merges two messages into one if they are consecutive lines from the same IRC user to the same
+/// channel, and fit in one Discord message together. Otherwise, returns them unchanged.
+fn merge(prev: Outgoing, next: Outgoing) -> Result<Outgoing, (Outgoing, Outgoing)> {
+    let mergeable = match (&prev.source, &next.source) {
+        (Some(a), Some(b)) => {
+            prev.channel == next.channel
+                && prev.webhook == next.webhook
+                && a.origin == b.origin
+                && a.sender == b.sender
+                && a.kind == b.kind
+                && is_from_irc(a)
+                && prev.content.chars().count() + 1 + next.content.chars().count()
+                    <= MAX_MESSAGE_LEN
+        }
+        _ => false,
+    };
+    if !mergeable {
+        return Err((prev, next));
+    }
+
+    let mut source = RelayMessage::clone(prev.source.as_ref().unwrap());
+    source.content = format!(
+        "{}\n{}",
+        source.content,
+        next.source.as_ref().unwrap().content
+    );
+    Ok(Outgoing {
+        channel: prev.channel,
+        webhook: prev.webhook,
+        content: Arc::new(format!("{}\n{}", prev.content, next.content)),
+        source: Some(Arc::new(source)),
+        sender: prev.sender,
+    })
+}
+
+/// Returns whether a message was sent on IRC.
+fn is_from_irc(msg: &RelayMessage) -> bool {
+    match msg.origin {
+        Endpoint::Irc(_, _) => true,
+        Endpoint::Discord(_) => false,
+    }
+}
+
+/// Runs the Discord client, restarting it with backoff whenever it fails. Only returns if it can't
+/// be created at all, or if events can no longer be relayed.
+fn supervise(
+    token: &str,
+    supervisor: &Arc<Supervisor>,
+    discord_send: UnboundedSender<RelayEvent>,
+) -> Error {
+    let mut backoff = Backoff::default();
+    loop {
+        supervisor.set_state(State::Connecting);
+        let handler = Handler(
+            discord_send.clone(),
+            RwLock::new(UserId(0)),
+            supervisor.clone(),
+        );
+        let mut discord = match Client::new(token, handler) {
+            Ok(discord) => discord,
+            Err(e) => return Error::from(SyncFailure::new(e)),
+        };
+
+        let started = Instant::now();
+        let result = discord.start();
+        if discord_send.is_closed() {
+            return format_err!("Discord client stopped because IRC hung up");
+        }
+        supervisor.set_state(State::Restarting);
+        match result {
+            Ok(()) => warn!("Discord client stopped"),
+            Err(e) => error!("Discord client failed: {}", e),
+        }
+
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        warn!("Restarting the Discord client in {}s", delay.as_secs());
+        sleep(delay);
+    }
+}
+
+/// Why posting a message to Discord failed.
+enum PostError {
+    /// Discord is rate limiting us, and says to wait for the given time, if it said.
+    RateLimited(Option<Duration>),
+
+    /// Something went wrong that may go away if the message is posted again.
+    Transient(Error),
+
+    /// Something went wrong that won't be fixed by posting the message again.
+    Permanent(Error),
+}
+
+impl From<serenity::Error> for PostError {
+    fn from(err: serenity::Error) -> PostError {
+        let is_transient = match &err {
+            serenity::Error::Http(HttpError::UnsuccessfulRequest(resp)) => {
+                if resp.status == StatusCode::TooManyRequests {
+                    return PostError::RateLimited(retry_after(resp));
+                }
+                resp.status.is_server_error()
+            }
+            serenity::Error::Hyper(_) | serenity::Error::Io(_) => true,
+            _ => false,
+        };
+        let err = SyncFailure::new(err).into();
+        if is_transient {
+            PostError::Transient(err)
+        } else {
+            PostError::Permanent(err)
+        }
+    }
+}
+
+/// Returns how long a rate-limited response says to wait before trying again, if it says.
+fn retry_after(resp: &HyperResponse) -> Option<Duration> {
+    ["Retry-After", "X-RateLimit-Reset-After"]
+        .iter()
+        .filter_map(|name| resp.headers.get_raw(name))
+        .filter_map(|values| values.first())
+        .filter_map(|value| std::str::from_utf8(value).ok())
+        .filter_map(|value| value.trim().parse::<f64>().ok())
+        .find(|&secs| secs >= 0.0)
+        .map(Duration::from_secs_f64)
+}
+
+/// Posts messages for one Discord channel in the order they arrive, so that a slow or
+/// rate-limited channel only holds up its own messages.
+fn post_in_order(msgs: mpsc::Receiver<Outgoing>) {
+    for msg in msgs {
+        deliver(&msg);
+    }
+}
+
+/// Posts a message to Discord, recording it in the message store. Messages that are too long are
+/// pasted if a paste store is configured, or split into several posts if not. Posts that keep
+/// failing are written to the dead-letter log.
+fn deliver(msg: &Outgoing) {
+    let pasted = match Config::paste() {
+        Some(ref config)
+            if config
+                .max_discord_len
+                .map_or(false, |max| msg.content.chars().count() > max) =>
+        {
+            // Pasting the original text keeps it readable, unlike the Discord markdown.
+            let text = msg
+                .source
+                .as_ref()
+                .map_or(msg.content.as_str(), |s| s.content.as_str());
+            match paste::store(config).paste(text) {
+                Ok(url) => Some(pasted_message(msg, &url)),
+                Err(err) => {
+                    error!("Couldn't paste a long message, so splitting it: {}", err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let pieces = match pasted {
+        Some(content) => vec![content],
+        None => split_message(&msg.content, MAX_MESSAGE_LEN),
+    };
+
+    for piece in pieces {
+        match post_with_retries(msg, &piece) {
+            Ok(Some(id)) => record_sent(msg, id),
+            Ok(None) => {}
+            Err(err) => {
+                let sender = msg.sender.as_ref().map(String::as_str);
+                dead_letters::record(msg.channel, sender, &piece, &err);
+            }
+        }
+    }
+}
+
+/// Posts part of a message, waiting out rate limits and retrying transient failures with backoff.
+/// Returns the ID of the message posted, if Discord said what it was.
+fn post_with_retries(msg: &Outgoing, content: &str) -> Result<Option<MessageId>, Error> {
+    let mut backoff = Backoff::default();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let delay = match post(msg, content) {
+            Ok(id) => return Ok(id),
+            // Being rate limited isn't a failure, so doesn't count as an attempt.
+            Err(PostError::RateLimited(retry_after)) => {
+                attempts -= 1;
+                retry_after.unwrap_or_else(|| backoff.next_delay())
+            }
+            Err(PostError::Transient(err)) if attempts < MAX_POST_ATTEMPTS => {
+                let delay = backoff.next_delay();
+                warn!(
+                    "Couldn't post to Discord channel {}, retrying in {}s: {}",
+                    msg.channel,
+                    delay.as_secs(),
+                    err
+                );
+                delay
+            }
+            Err(PostError::Transient(err)) | Err(PostError::Permanent(err)) => return Err(err),
+        };
+        sleep(delay);
+    }
+}
+
+/// Posts part of a message once, through its webhook if it has one.
+fn post(msg: &Outgoing, content: &str) -> Result<Option<MessageId>, PostError> {
+    match (&msg.webhook, &msg.sender) {
+        (Some(url), Some(sender)) => execute_webhook(url, sender, content),
+        _ => Ok(Some(ChannelId(msg.channel).say(content)?.id)),
+    }
+}
+
+/// Returns the message to post in place of one that was pasted at the given URL.
+fn pasted_message(msg: &Outgoing, url: &str) -> String {
+    match (&msg.webhook, &msg.sender) {
+        (Some(_), Some(_)) => format!("Sent a long message: {}", url),
+        (None, Some(sender)) => format!(
+            "__**{}**__ sent a long message: {}",
+            escape_markdown(sender),
+            url
+        ),
+        (_, None) => format!("A long message: {}", url),
+    }
+}
+
+/// Records a message posted to Discord in the message store, if it was relayed from IRC.
+fn record_sent(msg: &Outgoing, id: MessageId) {
+    if let Some(ref source) = msg.source {
+        if let Endpoint::Irc(ref irc_network, ref irc_channel) = source.origin {
+            MessageStore::record(Entry {
+                discord_channel: msg.channel,
+                discord_message: id.0,
+                irc_network: irc_network.clone(),
+                irc_channel: irc_channel.clone(),
+                sender: source.sender.clone(),
+                from_irc: true,
+                content: source.content.clone(),
+                timestamp: Entry::now(),
             });
+        }
+    }
+}
 
-            Either::A(
-                discord_recv
-                    .map_err(|()| unreachable!())
-                    .for_each(|(chan, msg)| {
-                        match ChannelId(chan).say(msg) {
-                            Ok(_) => {}
-                            Err(err) => {
-                                error!("{}", err);
-                            }
-                        }
-                        Ok(())
-                    })
-                    .and_then(|()| {
-                        end_recv
-                            .map_err(|_| format_err!("Discord client thread panicked!"))
-                            .and_then(err)
-                    }),
-            )
+/// Posts a message through the webhook with the given URL, using the given author name and an
+/// avatar picked from it. Returns the ID of the message, if Discord said what it was.
+fn execute_webhook(url: &str, author: &str, msg: &str) -> Result<Option<MessageId>, PostError> {
+    let (id, token) = parse_webhook_url(url)
+        .ok_or_else(|| PostError::Permanent(format_err!("Invalid webhook URL: {}", url)))?;
+    let map = ExecuteWebhook::default()
+        .content(msg)
+        .username(webhook_username(author))
+        .avatar_url(&avatar_url(author))
+        .0;
+    let msg = http::execute_webhook(id, token, true, &map)?;
+    Ok(msg.map(|msg| msg.id))
+}
+
+/// Discord rejects webhook names containing these words, whatever their case.
+const FORBIDDEN_NAME_PARTS: [&str; 2] = ["discord", "clyde"];
+/// Discord's limit on the length of a webhook name, in characters.
+const MAX_WEBHOOK_NAME_LEN: usize = 80;
+
+/// Turns a sender's name into one Discord accepts for a webhook post, so a nick like `clyde` or
+/// `everyone` doesn't get every message it sends rejected. Forbidden words are broken up with a
+/// zero-width space and over-long names are truncated.
+fn webhook_username(name: &str) -> String {
+    let mut name = name.to_owned();
+    for part in &FORBIDDEN_NAME_PARTS {
+        let mut start = 0;
+        while let Some(pos) = name[start..].to_ascii_lowercase().find(part) {
+            let split = start + pos + 1;
+            name.insert(split, ZERO_WIDTH_SPACE);
+            start = split + ZERO_WIDTH_SPACE.len_utf8();
         }
-        Err(e) => Either::B(err(Error::from(SyncFailure::new(e)))),
     }
+    if name.eq_ignore_ascii_case("everyone") || name.eq_ignore_ascii_case("here") {
+        name.push(ZERO_WIDTH_SPACE);
+    }
+    name.chars().take(MAX_WEBHOOK_NAME_LEN).collect()
+}
+
+/// Splits a webhook URL of the form `https://discordapp.com/api/webhooks/<id>/<token>` into the
+/// ID and token.
+fn parse_webhook_url(url: &str) -> Option<(u64, &str)> {
+    let mut parts = url.trim_end_matches('/').rsplit('/');
+    let token = parts.next()?;
+    let id = parts.next()?.parse().ok()?;
+    if parts.next() == Some("webhooks") {
+        Some((id, token))
+    } else {
+        None
+    }
+}
+
+/// Picks one of Discord's default avatars based on the nick, so a given IRC user keeps the same
+/// avatar between messages.
+fn avatar_url(nick: &str) -> String {
+    let hash = nick
+        .bytes()
+        .fold(0u32, |h, b| h.wrapping_mul(31).wrapping_add(u32::from(b)));
+    format!("https://cdn.discordapp.com/embed/avatars/{}.png", hash % 5)
+}
+
+/// Returns the nickname of a user in the guild the channel is in, if they have one.
+fn nickname(channel: ChannelId, user: UserId) -> Option<String> {
+    let cache = CACHE.read();
+    let guild_id = cache.guild_channel(channel)?.read().guild_id;
+    cache.member(guild_id, user)?.nick
 }
 
-struct Handler(UnboundedSender<(u64, String, String)>, RwLock<UserId>);
+/// Returns whether the webhook with the given ID is one of ours, so posts made through it aren't
+/// relayed back.
+fn is_own_webhook(id: u64) -> bool {
+    Config::webhook_urls()
+        .iter()
+        .filter_map(|url| parse_webhook_url(url))
+        .any(|(webhook_id, _)| webhook_id == id)
+}
+
+/// Returns the most recent store entry for a Discord message that was relayed to IRC.
+fn last_relayed(id: u64) -> Option<Entry> {
+    MessageStore::by_discord_message(id)
+        .into_iter()
+        .filter(|e| !e.from_irc)
+        .last()
+}
+
+struct Handler(UnboundedSender<RelayEvent>, RwLock<UserId>, Arc<Supervisor>);
+
+impl Handler {
+    /// Sends an event to the other side, shutting down if it has hung up.
+    fn send(&self, ctx: &Context, event: RelayEvent) {
+        if let Err(err) = self.0.unbounded_send(event) {
+            error!("{}", err);
+            ctx.quit();
+        }
+    }
+}
 
 impl EventHandler for Handler {
     fn ready(&self, _ctx: Context, ready: Ready) {
         *self.1.write() = ready.user.id;
+        self.2.connected();
     }
 
     fn message(&self, ctx: Context, msg: Message) {
         if msg.author.id == *self.1.read() {
             return;
         }
+        if msg.webhook_id.map_or(false, |id| is_own_webhook(id.0)) {
+            return;
+        }
 
-        let content = get_content(&msg);
-        println!("content = {}", content);
-        let chan_id = msg.channel_id.0;
-        let author = msg.author.name;
-        let iter = once((chan_id, author.clone(), content)).chain(
-            msg.attachments
-                .iter()
-                .map(|a| (chan_id, author.clone(), a.url.clone())),
-        );
-        for data in iter {
-            if let Err(err) = self.0.unbounded_send(data) {
-                error!("{}", err);
-                ctx.quit();
-                break;
+        let msg = RelayMessage {
+            origin: Endpoint::Discord(msg.channel_id.0),
+            id: Some(msg.id.0),
+            content: get_content(&msg),
+            attachments: msg.attachments.iter().map(|a| a.url.clone()).collect(),
+            nickname: nickname(msg.channel_id, msg.author.id),
+            kind: MessageKind::Normal,
+            sender: msg.author.name,
+        };
+        self.send(&ctx, RelayEvent::Message(msg));
+    }
+
+    fn message_update(&self, ctx: Context, update: MessageUpdateEvent) {
+        let content = match update.content {
+            Some(ref content) => content,
+            None => return,
+        };
+        if let Some(ref author) = update.author {
+            // Messages posted through a webhook are authored by the webhook itself.
+            if author.id == *self.1.read() || is_own_webhook(author.id.0) {
+                return;
             }
         }
+        if MessageStore::by_discord_message(update.id.0)
+            .iter()
+            .any(|e| e.from_irc)
+        {
+            return;
+        }
+        let mentions = update.mentions.as_ref().map_or(&[][..], |m| &m[..]);
+        let content = get_content_with_mentions(content, mentions);
+
+        let old = last_relayed(update.id.0);
+        let (sender, nickname) = match (&update.author, &old) {
+            (Some(author), _) => (author.name.clone(), nickname(update.channel_id, author.id)),
+            (None, Some(old)) => (old.sender.clone(), None),
+            (None, None) => return,
+        };
+        let old_content = old.map(|old| old.content);
+        if old_content.as_ref() == Some(&content) {
+            // Discord sends updates when embeds are added, even if the text didn't change.
+            return;
+        }
+
+        let new = RelayMessage {
+            origin: Endpoint::Discord(update.channel_id.0),
+            id: Some(update.id.0),
+            sender,
+            nickname,
+            kind: MessageKind::Normal,
+            content,
+            attachments: Vec::new(),
+        };
+        self.send(&ctx, RelayEvent::Edit(RelayEdit { old_content, new }));
+    }
+
+    fn message_delete(&self, ctx: Context, channel: ChannelId, id: MessageId) {
+        if let Some(entry) = last_relayed(id.0) {
+            self.send(
+                &ctx,
+                RelayEvent::Delete(RelayMessage {
+                    origin: Endpoint::Discord(channel.0),
+                    id: Some(id.0),
+                    sender: entry.sender,
+                    nickname: None,
+                    kind: MessageKind::Normal,
+                    content: entry.content,
+                    attachments: Vec::new(),
+                }),
+            );
+        }
     }
 
     fn guild_member_update(&self, _: Context, _: Option<Member>, new: Member) {
@@ -111,3 +716,52 @@ impl EventHandler for Handler {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordinary_names_are_unchanged() {
+        assert_eq!(webhook_username("alice"), "alice");
+        assert_eq!(webhook_username("everyone_"), "everyone_");
+    }
+
+    #[test]
+    fn forbidden_words_are_broken_up() {
+        assert_eq!(webhook_username("clyde"), "c\u{200B}lyde");
+        assert_eq!(webhook_username("DiscordFan"), "D\u{200B}iscordFan");
+        assert_eq!(
+            webhook_username("discord_discord"),
+            "d\u{200B}iscord_d\u{200B}iscord"
+        );
+        assert_eq!(
+            webhook_username("ClYdE-discord"),
+            "C\u{200B}lYdE-d\u{200B}iscord"
+        );
+    }
+
+    #[test]
+    fn mass_mention_names_are_changed() {
+        assert_eq!(webhook_username("everyone"), "everyone\u{200B}");
+        assert_eq!(webhook_username("Here"), "Here\u{200B}");
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let name = "é".repeat(100);
+        assert_eq!(
+            webhook_username(&name).chars().count(),
+            MAX_WEBHOOK_NAME_LEN
+        );
+    }
+
+    #[test]
+    fn parses_webhook_urls() {
+        assert_eq!(
+            parse_webhook_url("https://discordapp.com/api/webhooks/123/abc"),
+            Some((123, "abc"))
+        );
+        assert_eq!(parse_webhook_url("https://example.com/123/abc"), None);
+    }
+}
@@ -0,0 +1,100 @@
+//! Splitting text into messages that fit on Discord.
+
+/// The most characters a Discord message may have.
+pub const MAX_MESSAGE_LEN: usize = 2000;
+
+/// The fence that starts or ends a code block.
+const FENCE: &str = "```";
+
+/// Splits a message into pieces of at most `max_len` characters, preferring to split between
+/// lines, then between words. A code block that is split is closed at the end of one piece and
+/// reopened at the start of the next, so the rest of it doesn't lose its formatting.
+pub fn split_message(content: &str, max_len: usize) -> Vec<String> {
+    if content.chars().count() <= max_len {
+        return vec![content.to_string()];
+    }
+
+    // Leave room to reopen a code block at the start of a piece and close it at the end.
+    let budget = max_len.saturating_sub(2 * (FENCE.len() + 1)).max(1);
+
+    let mut pieces = Vec::new();
+    let mut rest = content;
+    let mut in_code_block = false;
+    while !rest.is_empty() {
+        let end = split_point(rest, budget);
+        let piece = &rest[..end];
+        let reopen = if in_code_block { "```\n" } else { "" };
+        if piece.matches(FENCE).count() % 2 == 1 {
+            in_code_block = !in_code_block;
+        }
+        let close = if in_code_block { "\n```" } else { "" };
+        pieces.push(format!("{}{}{}", reopen, piece, close));
+
+        // Drop the newline or space that was split at, but keep any indentation after it.
+        rest = &rest[end..];
+        if let Some(c) = rest.chars().next().filter(|c| c.is_whitespace()) {
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    pieces
+}
+
+/// Returns where to split off the first piece of some text, so the piece has at most `max_len`
+/// characters. The piece is never empty.
+fn split_point(text: &str, max_len: usize) -> usize {
+    let end = match text.char_indices().nth(max_len) {
+        Some((end, _)) => end,
+        None => return text.len(),
+    };
+    if text[end..].starts_with(char::is_whitespace) {
+        return end;
+    }
+    match text[..end].rfind('\n') {
+        Some(newline) if newline > 0 => newline,
+        _ => match text[..end].rfind(char::is_whitespace) {
+            Some(space) if space > 0 => space,
+            _ => end,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_messages_are_unchanged() {
+        assert_eq!(split_message("hello world", 20), vec!["hello world"]);
+    }
+
+    #[test]
+    fn prefers_splitting_between_lines() {
+        let content = format!("{}\n{} {}", "a".repeat(10), "b".repeat(5), "c".repeat(5));
+        assert_eq!(
+            split_message(&content, 20),
+            vec![
+                "a".repeat(10),
+                format!("{} {}", "b".repeat(5), "c".repeat(5))
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_characters_rather_than_bytes() {
+        let content = "\u{e9}".repeat(30);
+        let pieces = split_message(&content, 20);
+        assert!(pieces.iter().all(|piece| piece.chars().count() <= 20));
+        assert_eq!(pieces.concat(), content);
+    }
+
+    #[test]
+    fn reopens_split_code_blocks() {
+        let content = format!("```\n{}\n```", "x\n".repeat(20));
+        let pieces = split_message(&content, 20);
+        assert!(pieces.len() > 1);
+        for piece in &pieces {
+            assert!(piece.chars().count() <= 20);
+            assert_eq!(piece.matches(FENCE).count(), 2, "{:?}", piece);
+        }
+    }
+}
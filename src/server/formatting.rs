@@ -0,0 +1,449 @@
+//! Translation between IRC formatting control codes and Discord markdown.
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::iter::Peekable;
+
+lazy_static! {
+    static ref URL_PATTERN: Regex = Regex::new(r"\b[a-zA-Z][a-zA-Z0-9+.-]*://\S+").unwrap();
+}
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const HEX_COLOR: char = '\x04';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1D';
+const STRIKETHROUGH: char = '\x1E';
+const UNDERLINE: char = '\x1F';
+const RESET: char = '\x0F';
+
+/// The styles that can be applied to a run of text.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct Styles {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    monospace: bool,
+}
+
+/// A style that can be expressed in Discord markdown.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Style {
+    Bold,
+    Italic,
+    Underline,
+    Strikethrough,
+}
+
+impl Style {
+    /// Returns the IRC control code that toggles the style.
+    fn irc_code(self) -> char {
+        match self {
+            Style::Bold => BOLD,
+            Style::Italic => ITALIC,
+            Style::Underline => UNDERLINE,
+            Style::Strikethrough => STRIKETHROUGH,
+        }
+    }
+}
+
+/// Converts a message containing IRC formatting codes into Discord markdown. Colours and reverse
+/// video have no Discord equivalent, and are stripped. Any text that Discord would interpret as
+/// markdown is escaped.
+pub fn irc_to_discord(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut styles = Styles::default();
+    let mut run = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let new_styles = match c {
+            BOLD => Styles {
+                bold: !styles.bold,
+                ..styles
+            },
+            ITALIC => Styles {
+                italic: !styles.italic,
+                ..styles
+            },
+            UNDERLINE => Styles {
+                underline: !styles.underline,
+                ..styles
+            },
+            STRIKETHROUGH => Styles {
+                strikethrough: !styles.strikethrough,
+                ..styles
+            },
+            MONOSPACE => Styles {
+                monospace: !styles.monospace,
+                ..styles
+            },
+            RESET => Styles::default(),
+            COLOR | HEX_COLOR => {
+                let (is_digit, max_len): (fn(&char) -> bool, _) = if c == COLOR {
+                    (char::is_ascii_digit, 2)
+                } else {
+                    (char::is_ascii_hexdigit, 6)
+                };
+                let has_foreground = skip_while_max(&mut chars, max_len, is_digit) > 0;
+                if has_foreground && chars.peek() == Some(&',') {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if lookahead.peek().map(is_digit).unwrap_or(false) {
+                        chars.next();
+                        skip_while_max(&mut chars, max_len, is_digit);
+                    }
+                }
+                continue;
+            }
+            REVERSE => continue,
+            _ => {
+                run.push(c);
+                continue;
+            }
+        };
+        if new_styles != styles {
+            push_run(&mut out, &run, styles);
+            run.clear();
+            styles = new_styles;
+        }
+    }
+    push_run(&mut out, &run, styles);
+    out
+}
+
+/// Advances the iterator past up to `max` characters matching the predicate, returning how many
+/// were skipped.
+fn skip_while_max<I: Iterator<Item = char>>(
+    chars: &mut Peekable<I>,
+    max: usize,
+    pred: fn(&char) -> bool,
+) -> usize {
+    for skipped in 0..max {
+        match chars.peek() {
+            Some(c) if pred(c) => {
+                chars.next();
+            }
+            _ => return skipped,
+        }
+    }
+    max
+}
+
+/// Appends a run of text with the given styles as Discord markdown.
+fn push_run(out: &mut String, run: &str, styles: Styles) {
+    let inner = run.trim();
+    if inner.is_empty() {
+        out.push_str(&escape_markdown(run));
+        return;
+    }
+
+    let start = run.len() - run.trim_start().len();
+    let end = start + inner.len();
+    let markers = [
+        (styles.underline, "__"),
+        (styles.bold, "**"),
+        (styles.italic, "*"),
+        (styles.strikethrough, "~~"),
+    ];
+
+    out.push_str(&run[..start]);
+    for &(_, marker) in markers.iter().filter(|&&(on, _)| on) {
+        out.push_str(marker);
+    }
+    if styles.monospace && !inner.contains('`') {
+        out.push('`');
+        out.push_str(inner);
+        out.push('`');
+    } else {
+        out.push_str(&escape_markdown(inner));
+    }
+    for &(_, marker) in markers.iter().rev().filter(|&&(on, _)| on) {
+        out.push_str(marker);
+    }
+    out.push_str(&run[end..]);
+}
+
+/// Escapes any characters in plain text that Discord would interpret as markdown. URLs are left
+/// alone, since Discord would include the backslashes in the link.
+pub fn escape_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for url in URL_PATTERN.find_iter(text) {
+        escape_markdown_into(&mut out, &text[last..url.start()], last == 0);
+        out.push_str(url.as_str());
+        last = url.end();
+    }
+    escape_markdown_into(&mut out, &text[last..], last == 0);
+    out
+}
+
+fn escape_markdown_into(out: &mut String, text: &str, at_line_start: bool) {
+    for (i, c) in text.char_indices() {
+        let needs_escape = match c {
+            '\\' | '*' | '_' | '~' | '`' | '|' | '[' | ']' => true,
+            '>' | '#' => at_line_start && text[..i].trim().is_empty(),
+            _ => false,
+        };
+        if needs_escape {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Converts a message containing Discord markdown into IRC formatting codes. Inline code becomes
+/// monospace, and code blocks are sent without their fences. URLs are passed through untouched.
+pub fn discord_to_irc(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut open: Vec<Style> = Vec::new();
+    let mut prev = None;
+    let mut urls = URL_PATTERN.find_iter(text).peekable();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let offset = text.len() - rest.len();
+        while urls.peek().map(|url| url.end() <= offset).unwrap_or(false) {
+            urls.next();
+        }
+        if let Some(url) = urls.peek().filter(|url| url.start() == offset) {
+            out.push_str(url.as_str());
+            prev = url.as_str().chars().last();
+            rest = &text[url.end()..];
+            continue;
+        }
+
+        if c == '\\' {
+            if let Some(escaped) = rest[1..].chars().next() {
+                if escaped.is_ascii_punctuation() {
+                    out.push(escaped);
+                    prev = Some(escaped);
+                    rest = &rest[1 + escaped.len_utf8()..];
+                    continue;
+                }
+            }
+        } else if rest.starts_with("```") {
+            if let Some(end) = rest[3..].find("```") {
+                let block = &rest[3..3 + end];
+                let block = match block.find('\n') {
+                    Some(n) if !block[..n].contains(char::is_whitespace) => &block[n + 1..],
+                    _ => block,
+                };
+                out.push_str(block.trim_matches('\n'));
+                prev = Some('`');
+                rest = &rest[end + 6..];
+                continue;
+            }
+        } else if c == '`' {
+            if let Some(end) = rest[1..].find('`') {
+                out.push(MONOSPACE);
+                out.push_str(&rest[1..1 + end]);
+                out.push(MONOSPACE);
+                prev = Some('`');
+                rest = &rest[end + 2..];
+                continue;
+            }
+        } else if let Some((style, marker)) = marker_at(rest) {
+            let after = &rest[marker.len()..];
+            let next = after.chars().next();
+            let toggles = if open.contains(&style) {
+                marker != "_" || !next.map(char::is_alphanumeric).unwrap_or(false)
+            } else {
+                let word_start = marker != "_" || !prev.map(char::is_alphanumeric).unwrap_or(false);
+                word_start
+                    && next.map(|c| !c.is_whitespace()).unwrap_or(false)
+                    && after.contains(marker)
+            };
+            if toggles {
+                if let Some(i) = open.iter().position(|&s| s == style) {
+                    open.remove(i);
+                } else {
+                    open.push(style);
+                }
+                out.push(style.irc_code());
+                prev = marker.chars().last();
+                rest = after;
+                continue;
+            }
+        }
+
+        out.push(c);
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    if !open.is_empty() {
+        out.push(RESET);
+    }
+    out
+}
+
+/// Describes an edit as a `s/old/new/` substitution, if the changed part is short enough that this
+/// is clearer than repeating the whole message.
+pub fn sed_diff(old: &str, new: &str) -> Option<String> {
+    const MAX_DIFF_LEN: usize = 40;
+
+    let old = old.split_whitespace().collect::<Vec<_>>();
+    let new = new.split_whitespace().collect::<Vec<_>>();
+    let prefix = old.iter().zip(&new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if (prefix == 0 && suffix == 0) || old == new {
+        return None;
+    }
+
+    let (mut start, mut old_end, mut new_end) = (prefix, old.len() - suffix, new.len() - suffix);
+    if start == old_end || start == new_end {
+        // Pure insertions and deletions need a word of context to be unambiguous.
+        if start > 0 {
+            start -= 1;
+        } else {
+            old_end += 1;
+            new_end += 1;
+        }
+    }
+    let removed = old[start..old_end].join(" ");
+    let added = new[start..new_end].join(" ");
+    if removed.is_empty() || removed.len() + added.len() > MAX_DIFF_LEN {
+        None
+    } else {
+        Some(format!("s/{}/{}/", removed, added))
+    }
+}
+
+/// Truncates text to at most the given number of characters, marking it with an ellipsis if
+/// anything was removed.
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((i, _)) => format!("{}…", text[..i].trim_end()),
+        None => text.to_string(),
+    }
+}
+
+/// Returns the style toggled by the markdown marker at the start of the string, if there is one.
+fn marker_at(text: &str) -> Option<(Style, &'static str)> {
+    const MARKERS: &[(&str, Style)] = &[
+        ("**", Style::Bold),
+        ("__", Style::Underline),
+        ("~~", Style::Strikethrough),
+        ("*", Style::Italic),
+        ("_", Style::Italic),
+    ];
+    MARKERS
+        .iter()
+        .find(|&&(marker, _)| text.starts_with(marker))
+        .map(|&(marker, style)| (style, marker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colours_are_stripped() {
+        assert_eq!(irc_to_discord("\x0304red\x03 plain"), "red plain");
+        assert_eq!(irc_to_discord("\x034,12both\x03"), "both");
+        assert_eq!(irc_to_discord("\x0412ab34hex\x04"), "hex");
+        assert_eq!(irc_to_discord("\x03reset"), "reset");
+        assert_eq!(irc_to_discord("\x03,5 no foreground"), ",5 no foreground");
+        assert_eq!(irc_to_discord("\x0312, comma"), ", comma");
+        assert_eq!(irc_to_discord("\x03123 three digits"), "3 three digits");
+    }
+
+    #[test]
+    fn styles_become_markdown() {
+        assert_eq!(irc_to_discord("\x02bold\x02 plain"), "**bold** plain");
+        assert_eq!(
+            irc_to_discord("\x02bold \x1ditalic\x02 tail\x1d"),
+            "**bold** ***italic*** *tail*"
+        );
+        assert_eq!(
+            irc_to_discord("\x1funder\x0f \x1estruck"),
+            "__under__ ~~struck~~"
+        );
+        assert_eq!(irc_to_discord("\x02unclosed"), "**unclosed**");
+        assert_eq!(irc_to_discord("\x16reversed\x16"), "reversed");
+    }
+
+    #[test]
+    fn monospace_becomes_inline_code() {
+        assert_eq!(irc_to_discord("\x11x = *y\x11"), "`x = *y`");
+        assert_eq!(irc_to_discord("\x11a`b\x11"), "a\\`b");
+    }
+
+    #[test]
+    fn markdown_in_irc_text_is_escaped() {
+        assert_eq!(irc_to_discord("2*3*4"), "2\\*3\\*4");
+        assert_eq!(irc_to_discord("snake_case"), "snake\\_case");
+        assert_eq!(
+            escape_markdown("> quote # not a heading"),
+            "\\> quote # not a heading"
+        );
+        assert_eq!(
+            escape_markdown("[text](https://evil)"),
+            "\\[text\\](https://evil)"
+        );
+        assert_eq!(escape_markdown("||spoiler||"), "\\|\\|spoiler\\|\\|");
+    }
+
+    #[test]
+    fn urls_are_not_escaped() {
+        assert_eq!(
+            escape_markdown("see https://example.com/a_b*c for _more_"),
+            "see https://example.com/a_b*c for \\_more\\_"
+        );
+        assert_eq!(
+            discord_to_irc("https://example.com/__init__.py"),
+            "https://example.com/__init__.py"
+        );
+    }
+
+    #[test]
+    fn markdown_becomes_styles() {
+        assert_eq!(
+            discord_to_irc("**b** __u__ ~~s~~"),
+            "\x02b\x02 \x1fu\x1f \x1es\x1e"
+        );
+        assert_eq!(discord_to_irc("_it_ and *it*"), "\x1dit\x1d and \x1dit\x1d");
+        assert_eq!(discord_to_irc("***both***"), "\x02\x1dboth\x02\x1d");
+        assert_eq!(discord_to_irc("**unclosed"), "**unclosed");
+        assert_eq!(discord_to_irc("a * b * c"), "a * b * c");
+    }
+
+    #[test]
+    fn underscores_inside_words_are_literal() {
+        assert_eq!(discord_to_irc("snake_case_name"), "snake_case_name");
+        assert_eq!(discord_to_irc("_snake_case_"), "\x1dsnake_case\x1d");
+    }
+
+    #[test]
+    fn escaped_markers_are_literal() {
+        assert_eq!(discord_to_irc("\\*not italic\\*"), "*not italic*");
+        assert_eq!(discord_to_irc("back\\\\slash"), "back\\slash");
+        assert_eq!(discord_to_irc("\\a"), "\\a");
+    }
+
+    #[test]
+    fn code_is_passed_through() {
+        assert_eq!(discord_to_irc("`*code*`"), "\x11*code*\x11");
+        assert_eq!(discord_to_irc("```rust\nfn main() {}\n```"), "fn main() {}");
+        assert_eq!(discord_to_irc("```two words```"), "two words");
+    }
+
+    #[test]
+    fn irc_round_trips_through_discord() {
+        let irc = "\x02bold\x02 and \x1dit\x1d and \x11code\x11";
+        assert_eq!(discord_to_irc(&irc_to_discord(irc)), irc);
+        let plain = "2*3 = snake_case [x] ~~";
+        assert_eq!(discord_to_irc(&irc_to_discord(plain)), plain);
+    }
+
+    #[test]
+    fn discord_round_trips_through_irc() {
+        let discord = "**bold** and *it* and __under__ and `code`";
+        assert_eq!(irc_to_discord(&discord_to_irc(discord)), discord);
+    }
+}
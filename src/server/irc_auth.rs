@@ -0,0 +1,592 @@
+//! Registration with the IRC server, and authentication with its services, either through SASL or
+//! by identifying with NickServ.
+
+use failure::Fallible;
+use irc::{
+    client::{data::config::Config as IrcConfig, ext::ClientExt, Client, IrcClient},
+    proto::{
+        caps::Capability,
+        command::{CapSubCommand, Command},
+        response::Response,
+        Message,
+    },
+};
+use log::{error, info, warn};
+use std::{
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
+};
+
+use crate::config::{IrcAuth, SaslMechanism};
+
+/// How long to wait for NickServ to reply before joining channels anyway.
+const NICKSERV_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How far through authenticating the connection is.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// Negotiating SASL, which has to finish before registration can.
+    Sasl,
+
+    /// Registering, after SASL succeeded (or wasn't attempted, if not `authenticated`).
+    Registering { authenticated: bool },
+
+    /// Waiting for NickServ to say whether identifying worked, until the deadline.
+    NickServ { deadline: Instant },
+
+    /// Finished, whether successfully or not.
+    Done,
+}
+
+/// The parts of an IRC client that authentication uses, so it can be tested without a server.
+trait AuthClient {
+    /// Sends a command to the server.
+    fn send_command(&self, command: Command) -> Fallible<()>;
+
+    /// Returns the nickname the client is configured with.
+    fn configured_nickname(&self) -> Fallible<String>;
+
+    /// Returns the nickname the client currently has.
+    fn nickname(&self) -> String;
+
+    /// Makes the server send something after the delay, so a deadline is checked even if it is
+    /// otherwise quiet.
+    fn wake_after(&self, delay: Duration);
+}
+
+impl AuthClient for IrcClient {
+    fn send_command(&self, command: Command) -> Fallible<()> {
+        Ok(self.send(command)?)
+    }
+
+    fn configured_nickname(&self) -> Fallible<String> {
+        Ok(self.config().nickname()?.to_string())
+    }
+
+    fn nickname(&self) -> String {
+        self.current_nickname().to_string()
+    }
+
+    fn wake_after(&self, delay: Duration) {
+        let client = self.clone();
+        spawn(move || {
+            sleep(delay);
+            // If the connection has dropped in the meantime, there's nothing left to wake.
+            let _ = client.send(Command::PING("janus".to_string(), None));
+        });
+    }
+}
+
+/// Registers a connection and authenticates it, telling the caller when it's safe to join
+/// channels.
+pub struct Authenticator {
+    auth: Option<IrcAuth>,
+    state: State,
+}
+
+impl Authenticator {
+    /// Creates an authenticator using the given settings, if any.
+    pub fn new(auth: Option<IrcAuth>) -> Authenticator {
+        Authenticator {
+            auth,
+            state: State::Registering {
+                authenticated: false,
+            },
+        }
+    }
+
+    /// Adds the client certificate to the IRC config, if one is configured. The channels are taken
+    /// out of the config, since the irc crate would join them without waiting for authentication,
+    /// and are joined along with the bound channels instead.
+    pub fn configure(&self, config: &mut IrcConfig) {
+        if let Some(auth) = &self.auth {
+            config.channels = None;
+            if auth.client_cert.is_some() {
+                config.client_cert_path = auth.client_cert.clone();
+                config.client_cert_pass = auth.client_cert_password.clone();
+            }
+        }
+    }
+
+    /// Registers the connection, requesting SASL first if it is configured.
+    pub fn register(&mut self, client: &IrcClient) -> Fallible<()> {
+        if self.auth.as_ref().and_then(|auth| auth.sasl).is_none() {
+            return Ok(client.identify()?);
+        }
+
+        // This is ClientExt::identify, except the capability negotiation is left open until SASL
+        // is done.
+        let config = client.config();
+        client.send_cap_req(&[Capability::Sasl])?;
+        if !config.password().is_empty() {
+            client.send(Command::PASS(config.password().to_string()))?;
+        }
+        client.send(Command::NICK(config.nickname()?.to_string()))?;
+        client.send(Command::USER(
+            config.username().to_string(),
+            "0".to_string(),
+            config.real_name().to_string(),
+        ))?;
+        self.state = State::Sasl;
+        Ok(())
+    }
+
+    /// Handles a message from the server. Returns true once the connection is registered and
+    /// authentication has either finished or failed, meaning channels should be joined.
+    pub fn handle(&mut self, client: &IrcClient, msg: &Message) -> Fallible<bool> {
+        self.handle_at(client, msg, Instant::now())
+    }
+
+    /// Handles a message from the server, received at the given time.
+    fn handle_at(
+        &mut self,
+        client: &impl AuthClient,
+        msg: &Message,
+        now: Instant,
+    ) -> Fallible<bool> {
+        let auth = match self.auth.clone() {
+            Some(auth) => auth,
+            None => return Ok(is_end_of_motd(msg)),
+        };
+
+        if self.state == State::Sasl && is_end_of_motd(msg) {
+            // The server finished registration without answering the capability request, so it
+            // can't do SASL.
+            warn!("The IRC server ignored the request to use SASL");
+            self.state = State::Registering {
+                authenticated: false,
+            };
+        }
+        if let State::NickServ { deadline } = self.state {
+            if now >= deadline {
+                warn!(
+                    "NickServ didn't reply within {}s, so joining channels anyway",
+                    NICKSERV_TIMEOUT.as_secs()
+                );
+                self.state = State::Done;
+                return Ok(true);
+            }
+        }
+
+        match (self.state, &msg.command) {
+            (State::Sasl, Command::CAP(_, CapSubCommand::ACK, _, Some(caps)))
+                if caps.split_whitespace().any(|cap| cap == "sasl") =>
+            {
+                let mechanism = match auth.sasl {
+                    Some(SaslMechanism::Plain) => "PLAIN",
+                    Some(SaslMechanism::External) => "EXTERNAL",
+                    None => unreachable!(),
+                };
+                client.send_command(Command::AUTHENTICATE(mechanism.to_string()))?;
+            }
+            (State::Sasl, Command::CAP(_, CapSubCommand::NAK, _, _)) => {
+                warn!("The IRC server doesn't support SASL");
+                self.finish_sasl(client, false)?;
+            }
+            (State::Sasl, Command::AUTHENTICATE(data)) if data == "+" => {
+                let response = match auth.sasl {
+                    Some(SaslMechanism::Plain) => {
+                        let account = self.account(client);
+                        let password = auth.password.as_ref().map_or("", String::as_str);
+                        base64::encode(&format!("{}\0{}\0{}", account, account, password))
+                    }
+                    _ => "+".to_string(),
+                };
+                client.send_command(Command::AUTHENTICATE(response))?;
+            }
+            (State::Sasl, Command::Response(Response::RPL_SASLSUCCESS, _, _)) => {
+                info!("Authenticated with SASL");
+                self.finish_sasl(client, true)?;
+            }
+            (State::Sasl, Command::Response(resp, _, text)) if is_sasl_failure(*resp) => {
+                error!(
+                    "SASL authentication failed: {}",
+                    text.as_ref().map_or("no reason given", String::as_str)
+                );
+                self.finish_sasl(client, false)?;
+            }
+            (State::Registering { authenticated }, _) if is_end_of_motd(msg) => {
+                if authenticated || !auth.nickserv || !self.identify_with_nickserv(client)? {
+                    self.state = State::Done;
+                    return Ok(true);
+                }
+                client.wake_after(NICKSERV_TIMEOUT);
+                self.state = State::NickServ {
+                    deadline: now + NICKSERV_TIMEOUT,
+                };
+            }
+            (State::NickServ { .. }, Command::Response(Response::RPL_LOGGEDIN, _, _)) => {
+                info!("Identified with NickServ");
+                self.state = State::Done;
+                return Ok(true);
+            }
+            (State::NickServ { .. }, Command::NOTICE(_, text))
+                if msg
+                    .source_nickname()
+                    .map_or(false, |nick| nick.eq_ignore_ascii_case("NickServ")) =>
+            {
+                match nickserv_result(text) {
+                    Some(true) => info!("Identified with NickServ: {}", text),
+                    Some(false) => error!("Couldn't identify with NickServ: {}", text),
+                    None => {
+                        info!("NickServ: {}", text);
+                        return Ok(false);
+                    }
+                }
+                self.state = State::Done;
+                return Ok(true);
+            }
+            _ => {}
+        }
+        Ok(false)
+    }
+
+    /// Ends capability negotiation, so registration can finish.
+    fn finish_sasl(&mut self, client: &impl AuthClient, authenticated: bool) -> Fallible<()> {
+        client.send_command(Command::CAP(None, CapSubCommand::END, None, None))?;
+        self.state = State::Registering { authenticated };
+        Ok(())
+    }
+
+    /// Identifies with NickServ, first ghosting whoever has our nickname if configured to.
+    /// Returns whether NickServ was asked to identify us.
+    fn identify_with_nickserv(&self, client: &impl AuthClient) -> Fallible<bool> {
+        let auth = self.auth.as_ref().unwrap();
+        let password = match &auth.password {
+            Some(password) => password,
+            None => {
+                warn!("Can't identify with NickServ without a password");
+                return Ok(false);
+            }
+        };
+
+        let nickname = client.configured_nickname()?;
+        let privmsg = |text| Command::PRIVMSG("NickServ".to_string(), text);
+        if auth.ghost && client.nickname() != nickname {
+            client.send_command(privmsg(format!("GHOST {} {}", nickname, password)))?;
+            client.send_command(Command::NICK(nickname))?;
+        }
+        let identify = match &auth.account {
+            Some(account) => format!("IDENTIFY {} {}", account, password),
+            None => format!("IDENTIFY {}", password),
+        };
+        client.send_command(privmsg(identify))?;
+        Ok(true)
+    }
+
+    /// Returns the account to log in to.
+    fn account(&self, client: &impl AuthClient) -> String {
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.account.clone())
+            .unwrap_or_else(|| client.configured_nickname().unwrap_or_default())
+    }
+}
+
+/// Returns whether a message marks the end of registration.
+fn is_end_of_motd(msg: &Message) -> bool {
+    matches!(
+        msg.command,
+        Command::Response(Response::RPL_ENDOFMOTD, _, _)
+            | Command::Response(Response::ERR_NOMOTD, _, _)
+    )
+}
+
+/// Returns whether a NickServ notice says identifying succeeded (`Some(true)`) or failed
+/// (`Some(false)`), or `None` if it is about something else, such as ghosting.
+fn nickserv_result(text: &str) -> Option<bool> {
+    const SUCCESS: &[&str] = &[
+        "you are now identified",
+        "you are now logged in",
+        "password accepted",
+    ];
+    const FAILURE: &[&str] = &[
+        "invalid password",
+        "incorrect password",
+        "password incorrect",
+        "is not registered",
+        "isn't registered",
+        "not a registered",
+        "access denied",
+    ];
+
+    let text = text.to_lowercase();
+    if SUCCESS.iter().any(|phrase| text.contains(phrase)) {
+        Some(true)
+    } else if FAILURE.iter().any(|phrase| text.contains(phrase)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Returns whether a response means SASL authentication failed.
+fn is_sasl_failure(resp: Response) -> bool {
+    matches!(
+        resp,
+        Response::ERR_NICKLOCKED
+            | Response::ERR_SASLFAIL
+            | Response::ERR_SASLTOOLONG
+            | Response::ERR_SASLABORT
+            | Response::ERR_SASLALREADY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A client that records what it is asked to send.
+    #[derive(Default)]
+    struct Recorder {
+        sent: RefCell<Vec<Command>>,
+        woken_after: RefCell<Vec<Duration>>,
+        nickname: String,
+    }
+
+    impl AuthClient for Recorder {
+        fn send_command(&self, command: Command) -> Fallible<()> {
+            self.sent.borrow_mut().push(command);
+            Ok(())
+        }
+
+        fn configured_nickname(&self) -> Fallible<String> {
+            Ok("janus".to_string())
+        }
+
+        fn nickname(&self) -> String {
+            self.nickname.clone()
+        }
+
+        fn wake_after(&self, delay: Duration) {
+            self.woken_after.borrow_mut().push(delay);
+        }
+    }
+
+    impl Recorder {
+        fn new() -> Recorder {
+            Recorder {
+                nickname: "janus".to_string(),
+                ..Recorder::default()
+            }
+        }
+
+        fn take_sent(&self) -> Vec<Command> {
+            self.sent.borrow_mut().drain(..).collect()
+        }
+    }
+
+    fn settings(sasl: Option<SaslMechanism>, nickserv: bool) -> IrcAuth {
+        IrcAuth {
+            sasl,
+            account: None,
+            password: Some("hunter2".to_string()),
+            client_cert: None,
+            client_cert_password: None,
+            nickserv,
+            ghost: false,
+        }
+    }
+
+    /// Returns an authenticator that has sent its registration, as `register` would.
+    fn registered(auth: IrcAuth) -> Authenticator {
+        let mut authenticator = Authenticator::new(Some(auth));
+        if authenticator.auth.as_ref().unwrap().sasl.is_some() {
+            authenticator.state = State::Sasl;
+        }
+        authenticator
+    }
+
+    fn handle(auth: &mut Authenticator, client: &Recorder, line: &str) -> bool {
+        handle_at(auth, client, line, Instant::now())
+    }
+
+    fn handle_at(auth: &mut Authenticator, client: &Recorder, line: &str, now: Instant) -> bool {
+        let msg = format!("{}\r\n", line).parse::<Message>().unwrap();
+        auth.handle_at(client, &msg, now).unwrap()
+    }
+
+    fn identify() -> Command {
+        Command::PRIVMSG("NickServ".to_string(), "IDENTIFY hunter2".to_string())
+    }
+
+    fn cap_end() -> Command {
+        Command::CAP(None, CapSubCommand::END, None, None)
+    }
+
+    const END_OF_MOTD: &str = ":irc.example.net 376 janus :End of /MOTD command.";
+
+    #[test]
+    fn without_auth_joins_after_the_motd() {
+        let client = Recorder::new();
+        let mut auth = Authenticator::new(None);
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":irc.example.net 001 janus :Welcome"
+        ));
+        assert!(handle(&mut auth, &client, END_OF_MOTD));
+        assert!(client.take_sent().is_empty());
+    }
+
+    #[test]
+    fn sasl_plain_logs_in() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(Some(SaslMechanism::Plain), true));
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":irc.example.net CAP * ACK :sasl"
+        ));
+        assert!(!handle(&mut auth, &client, "AUTHENTICATE +"));
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":irc.example.net 903 janus :SASL authentication successful"
+        ));
+        assert_eq!(
+            client.take_sent(),
+            vec![
+                Command::AUTHENTICATE("PLAIN".to_string()),
+                Command::AUTHENTICATE(base64::encode("janus\0janus\0hunter2")),
+                cap_end(),
+            ]
+        );
+        assert!(handle(&mut auth, &client, END_OF_MOTD));
+        assert!(client.take_sent().is_empty());
+    }
+
+    #[test]
+    fn failed_sasl_falls_back_to_nickserv() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(Some(SaslMechanism::Plain), true));
+        handle(&mut auth, &client, ":irc.example.net CAP * ACK :sasl");
+        handle(&mut auth, &client, "AUTHENTICATE +");
+        client.take_sent();
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":irc.example.net 904 janus :SASL authentication failed"
+        ));
+        assert_eq!(client.take_sent(), vec![cap_end()]);
+        assert!(!handle(&mut auth, &client, END_OF_MOTD));
+        assert_eq!(client.take_sent(), vec![identify()]);
+        assert!(handle(
+            &mut auth,
+            &client,
+            ":NickServ!s@services. NOTICE janus :You are now identified for janus."
+        ));
+    }
+
+    #[test]
+    fn ignored_sasl_request_falls_back_to_nickserv() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(Some(SaslMechanism::Plain), true));
+        assert!(!handle(&mut auth, &client, END_OF_MOTD));
+        assert_eq!(client.take_sent(), vec![identify()]);
+
+        let mut auth = registered(settings(Some(SaslMechanism::External), false));
+        assert!(handle(&mut auth, &client, END_OF_MOTD));
+    }
+
+    #[test]
+    fn only_clear_nickserv_replies_finish() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(None, true));
+        handle(&mut auth, &client, END_OF_MOTD);
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":NickServ!s@services. NOTICE janus :This nickname is registered. Please choose a \
+             different nickname, or identify via /msg NickServ IDENTIFY <password>."
+        ));
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":ChanServ!s@services. NOTICE janus :Invalid password for janus."
+        ));
+        assert!(handle(
+            &mut auth,
+            &client,
+            ":NickServ!s@services. NOTICE janus :Invalid password for janus."
+        ));
+    }
+
+    #[test]
+    fn logged_in_numeric_finishes() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(None, true));
+        handle(&mut auth, &client, END_OF_MOTD);
+        assert!(handle(
+            &mut auth,
+            &client,
+            ":irc.example.net 900 janus janus!j@host janus :You are now logged in as janus"
+        ));
+    }
+
+    #[test]
+    fn nickserv_times_out() {
+        let client = Recorder::new();
+        let mut auth = registered(settings(None, true));
+        let start = Instant::now();
+        handle_at(&mut auth, &client, END_OF_MOTD, start);
+        assert_eq!(*client.woken_after.borrow(), vec![NICKSERV_TIMEOUT]);
+        let pong = ":irc.example.net PONG irc.example.net :janus";
+        assert!(!handle_at(
+            &mut auth,
+            &client,
+            pong,
+            start + NICKSERV_TIMEOUT / 2
+        ));
+        assert!(handle_at(
+            &mut auth,
+            &client,
+            pong,
+            start + NICKSERV_TIMEOUT
+        ));
+        assert!(!handle_at(
+            &mut auth,
+            &client,
+            pong,
+            start + NICKSERV_TIMEOUT * 2
+        ));
+    }
+
+    #[test]
+    fn ghosts_before_identifying() {
+        let client = Recorder {
+            nickname: "janus_".to_string(),
+            ..Recorder::default()
+        };
+        let mut auth = registered(IrcAuth {
+            ghost: true,
+            ..settings(None, true)
+        });
+        handle(&mut auth, &client, END_OF_MOTD);
+        assert_eq!(
+            client.take_sent(),
+            vec![
+                Command::PRIVMSG("NickServ".to_string(), "GHOST janus hunter2".to_string()),
+                Command::NICK("janus".to_string()),
+                identify(),
+            ]
+        );
+        assert!(!handle(
+            &mut auth,
+            &client,
+            ":NickServ!s@services. NOTICE janus_ :\x02janus\x02 has been ghosted."
+        ));
+    }
+
+    #[test]
+    fn without_a_password_nickserv_is_skipped() {
+        let client = Recorder::new();
+        let mut auth = registered(IrcAuth {
+            password: None,
+            ..settings(None, true)
+        });
+        assert!(handle(&mut auth, &client, END_OF_MOTD));
+        assert!(client.take_sent().is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use serenity::{
+    model::{
+        guild::Guild,
+        id::{ChannelId, RoleId, UserId},
+    },
+    prelude::RwLock,
+    CACHE,
+};
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{config::Binding, server::discord_side::ID_TO_NICK};
+
+lazy_static! {
+    static ref ADDRESS_PATTERN: Regex =
+        Regex::new(r"^(?P<name>[^\s:,@][^\s:,]*)[:,](?:\s|$)").unwrap();
+    static ref AT_MENTION_PATTERN: Regex =
+        Regex::new(r"(?:^|\s)(?P<mention>@(?P<name>[\w\-\[\]\\`^{}|.]*[\w\-\[\]\\`^{}|]))")
+            .unwrap();
+    static ref MASS_MENTION_PATTERN: Regex = Regex::new(r"@(?P<name>everyone|here)\b").unwrap();
+    static ref ROLE_MENTION_PATTERN: Regex = Regex::new(r"<@&(?P<id>[0-9]+)>").unwrap();
+}
+
+/// A zero-width space, which stops Discord from recognizing a mention without changing how the
+/// text looks.
+pub(crate) const ZERO_WIDTH_SPACE: char = '\u{200B}';
+
+/// Replaces the names of Discord users addressed (`name: ...`) or mentioned (`@name`) in an IRC
+/// message with mentions that ping them. Names that match more than one user are left alone.
+pub fn add_mentions(content: &str, channel: u64) -> String {
+    let address = ADDRESS_PATTERN
+        .captures(content)
+        .and_then(|captures| captures.name("name"))
+        .map(|name| (name.start(), name.end(), name.as_str()));
+    let at_mentions = AT_MENTION_PATTERN.captures_iter(content).map(|captures| {
+        let mention = captures.name("mention").unwrap();
+        (mention.start(), mention.end(), &captures["name"])
+    });
+
+    let mut out = String::with_capacity(content.len());
+    let mut last = 0;
+    for (start, end, name) in address.into_iter().chain(at_mentions) {
+        if start < last {
+            continue;
+        }
+        if let Some(id) = find_user(name, channel) {
+            out.push_str(&content[last..start]);
+            out.push_str(&format!("<@{}>", id));
+            last = end;
+        }
+    }
+    out.push_str(&content[last..]);
+    out
+}
+
+/// Stops `@everyone`, `@here` and role mentions in a message for the given Discord channel from
+/// pinging anyone, unless the binding allows them. Role mentions are replaced with the name of the
+/// role. This must be run on the final Discord text, since formatting codes stripped along the way
+/// could otherwise hide a mention from it.
+pub fn neutralise_mentions(content: &str, binding: &Binding, channel: u64) -> String {
+    let guild = guild_for_channel(channel);
+    neutralise_mentions_with(content, binding, |id| {
+        guild
+            .as_ref()
+            .and_then(|guild| guild.read().roles.get(&RoleId(id)).map(|r| r.name.clone()))
+    })
+}
+
+/// Neutralises mentions as `neutralise_mentions` does, looking up the names of roles with the given
+/// function.
+fn neutralise_mentions_with(
+    content: &str,
+    binding: &Binding,
+    role_name: impl Fn(u64) -> Option<String>,
+) -> String {
+    let content = MASS_MENTION_PATTERN.replace_all(content, |captures: &Captures| {
+        let name = &captures["name"];
+        if binding
+            .allowed_mentions
+            .iter()
+            .any(|allowed| allowed == name)
+        {
+            captures[0].to_string()
+        } else {
+            format!("@{}{}", ZERO_WIDTH_SPACE, name)
+        }
+    });
+
+    let content = ROLE_MENTION_PATTERN.replace_all(&content, |captures: &Captures| {
+        let id = captures["id"].parse::<u64>().unwrap_or(0);
+        if binding.allowed_roles.contains(&id) {
+            return captures[0].to_string();
+        }
+        let name = role_name(id).unwrap_or_else(|| "deleted-role".to_string());
+        format!("@{}{}", ZERO_WIDTH_SPACE, name)
+    });
+    content.into_owned()
+}
+
+/// Returns the guild the channel is in, if it is in the cache.
+fn guild_for_channel(channel: u64) -> Option<Arc<RwLock<Guild>>> {
+    let cache = CACHE.read();
+    let chan = cache.guild_channel(ChannelId(channel))?;
+    let guild_id = chan.read().guild_id;
+    cache.guild(guild_id)
+}
+
+/// Finds the ID of the only user with the given nickname or username, preferring members of the
+/// guild the channel is in.
+fn find_user(name: &str, channel: u64) -> Option<u64> {
+    let name = name.to_lowercase();
+    let mut ids = ID_TO_NICK
+        .read()
+        .iter()
+        .filter(|(_, nick)| nick.trim_start_matches('@').to_lowercase() == name)
+        .map(|(&id, _)| id)
+        .collect::<HashSet<_>>();
+
+    if let Some(guild) = guild_for_channel(channel) {
+        let guild = guild.read();
+        ids.retain(|&id| guild.members.contains_key(&UserId(id)));
+        for (id, member) in &guild.members {
+            let user = member.user.read();
+            let nick_matches = member
+                .nick
+                .as_ref()
+                .map(|nick| nick.to_lowercase() == name)
+                .unwrap_or(false);
+            if nick_matches || user.name.to_lowercase() == name {
+                ids.insert(id.0);
+            }
+        }
+    }
+
+    if ids.len() == 1 {
+        ids.into_iter().next()
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::formatting::irc_to_discord;
+
+    fn neutralise(content: &str, binding: &Binding) -> String {
+        neutralise_mentions_with(content, binding, |id| {
+            if id == 123 {
+                Some("mods".to_string())
+            } else {
+                None
+            }
+        })
+    }
+
+    fn binding() -> Binding {
+        Binding::new(1, "#chan".to_string())
+    }
+
+    #[test]
+    fn mass_mentions_are_neutralised() {
+        assert_eq!(
+            neutralise("hi @everyone and @here", &binding()),
+            "hi @\u{200B}everyone and @\u{200B}here"
+        );
+        assert_eq!(
+            neutralise("mail@everyone.org", &binding()),
+            "mail@\u{200B}everyone.org"
+        );
+        assert_eq!(neutralise("@everyoneelse", &binding()), "@everyoneelse");
+    }
+
+    #[test]
+    fn role_mentions_become_names() {
+        assert_eq!(neutralise("ping <@&123>", &binding()), "ping @\u{200B}mods");
+        assert_eq!(neutralise("<@&456>", &binding()), "@\u{200B}deleted-role");
+        assert_eq!(neutralise("<@456>", &binding()), "<@456>");
+    }
+
+    #[test]
+    fn allowed_mentions_are_kept() {
+        let mut binding = binding();
+        binding.allowed_mentions = vec!["here".to_string()];
+        binding.allowed_roles = vec![123];
+        assert_eq!(
+            neutralise("@here @everyone <@&123>", &binding),
+            "@here @\u{200B}everyone <@&123>"
+        );
+    }
+
+    #[test]
+    fn formatting_codes_cannot_hide_mentions() {
+        for &text in &[
+            "@\x16everyone",
+            "@\x03everyone",
+            "@\x0304,05everyone",
+            "@\x02\x02everyone",
+        ] {
+            assert_eq!(
+                neutralise(&irc_to_discord(text), &binding()),
+                "@\u{200B}everyone",
+                "{:?}",
+                text
+            );
+        }
+        assert_eq!(
+            neutralise(&irc_to_discord("<@&\x0f123>"), &binding()),
+            "@\u{200B}mods"
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Flood control for messages sent to IRC: a token bucket limiting how fast lines are sent, and a
+//! queue that takes turns between channels so a busy one can't hold up the rest.
+
+use std::{
+    collections::{BTreeSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::config::Overflow;
+
+/// The most senders to name in a summary of skipped messages.
+const MAX_SUMMARY_SENDERS: usize = 5;
+
+/// Limits sending to a burst of lines, after which lines are sent at a steady rate.
+pub struct TokenBucket {
+    /// The number of lines that can be sent right away.
+    tokens: f64,
+
+    /// When `tokens` was last topped up.
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a bucket that allows a full burst straight away.
+    pub fn new(burst: usize) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available at the given time. Otherwise, returns how long to wait
+    /// for one. The bucket holds at most `burst` tokens, and refills at `rate` tokens a second.
+    pub fn take(&mut self, burst: usize, rate: f64, now: Instant) -> Option<Duration> {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst as f64);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+/// An entry in a channel's queue.
+enum Entry {
+    /// A line to send.
+    Line(String),
+
+    /// Messages that were skipped because the queue was full, with who sent them.
+    Skipped {
+        count: usize,
+        senders: BTreeSet<String>,
+    },
+}
+
+/// The lines waiting to be sent to each channel.
+#[derive(Default)]
+pub struct SendQueue {
+    /// The channels with something to send, in the order they will take turns.
+    channels: VecDeque<(String, VecDeque<Entry>)>,
+
+    /// The number of entries across every channel.
+    depth: usize,
+}
+
+impl SendQueue {
+    /// Returns the number of lines waiting to be sent, counting each summary of skipped messages
+    /// as one.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Adds the lines of a message to a channel's queue. If the queue already holds `max_depth`
+    /// lines, the message is dropped or summarised instead, depending on `overflow`. Returns
+    /// whether the message was queued in full.
+    pub fn push(
+        &mut self,
+        channel: &str,
+        sender: Option<&str>,
+        lines: Vec<String>,
+        max_depth: usize,
+        overflow: Overflow,
+    ) -> bool {
+        if self.depth >= max_depth && overflow == Overflow::Drop {
+            return false;
+        }
+
+        let queue = match self.channels.iter().position(|(c, _)| c == channel) {
+            Some(i) => &mut self.channels[i].1,
+            None => {
+                self.channels
+                    .push_back((channel.to_string(), VecDeque::new()));
+                &mut self.channels.back_mut().unwrap().1
+            }
+        };
+        if self.depth < max_depth {
+            self.depth += lines.len();
+            queue.extend(lines.into_iter().map(Entry::Line));
+            return true;
+        }
+
+        if !matches!(queue.back(), Some(Entry::Skipped { .. })) {
+            queue.push_back(Entry::Skipped {
+                count: 0,
+                senders: BTreeSet::new(),
+            });
+            self.depth += 1;
+        }
+        if let Some(Entry::Skipped { count, senders }) = queue.back_mut() {
+            *count += 1;
+            senders.extend(sender.map(str::to_string));
+        }
+        false
+    }
+
+    /// Takes the next line to send and the channel to send it to, taking turns between channels.
+    pub fn pop(&mut self) -> Option<(String, String)> {
+        let (channel, mut queue) = self.channels.pop_front()?;
+        let line = match queue.pop_front()? {
+            Entry::Line(line) => line,
+            Entry::Skipped { count, senders } => summarise(count, &senders),
+        };
+        self.depth -= 1;
+        if !queue.is_empty() {
+            self.channels.push_back((channel.clone(), queue));
+        }
+        Some((channel, line))
+    }
+}
+
+/// Describes messages that were skipped to avoid flooding.
+fn summarise(count: usize, senders: &BTreeSet<String>) -> String {
+    let messages = if count == 1 { "message" } else { "messages" };
+    let mut names = senders
+        .iter()
+        .take(MAX_SUMMARY_SENDERS)
+        .cloned()
+        .collect::<Vec<_>>();
+    if senders.len() > MAX_SUMMARY_SENDERS {
+        names.push("others".to_string());
+    }
+    if names.is_empty() {
+        format!("({} {} skipped to avoid flooding)", count, messages)
+    } else {
+        format!(
+            "({} {} from {} skipped to avoid flooding)",
+            count,
+            messages,
+            names.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    fn drain(queue: &mut SendQueue) -> Vec<(String, String)> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_a_steady_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2);
+        assert_eq!(bucket.take(2, 0.5, start), None);
+        assert_eq!(bucket.take(2, 0.5, start), None);
+        assert_eq!(bucket.take(2, 0.5, start), Some(Duration::from_secs(2)));
+        assert_eq!(bucket.take(2, 0.5, start + Duration::from_secs(2)), None);
+    }
+
+    #[test]
+    fn bucket_doesnt_fill_past_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1);
+        let later = start + Duration::from_secs(60);
+        assert_eq!(bucket.take(1, 1.0, later), None);
+        assert!(bucket.take(1, 1.0, later).is_some());
+    }
+
+    #[test]
+    fn channels_take_turns() {
+        let mut queue = SendQueue::default();
+        queue.push("#a", None, lines(&["a1", "a2", "a3"]), 10, Overflow::Drop);
+        queue.push("#b", None, lines(&["b1"]), 10, Overflow::Drop);
+        queue.push("#a", None, lines(&["a4"]), 10, Overflow::Drop);
+        assert_eq!(queue.depth(), 5);
+        let sent = drain(&mut queue)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>();
+        assert_eq!(sent, vec!["a1", "b1", "a2", "a3", "a4"]);
+        assert_eq!(queue.depth(), 0);
+    }
+
+    #[test]
+    fn overflowing_messages_are_dropped() {
+        let mut queue = SendQueue::default();
+        assert!(queue.push("#a", None, lines(&["a1", "a2"]), 2, Overflow::Drop));
+        assert!(!queue.push("#a", None, lines(&["a3"]), 2, Overflow::Drop));
+        assert_eq!(drain(&mut queue).len(), 2);
+    }
+
+    #[test]
+    fn overflowing_messages_are_summarised() {
+        let mut queue = SendQueue::default();
+        queue.push("#a", Some("x"), lines(&["a1"]), 1, Overflow::Summarise);
+        queue.push("#a", Some("y"), lines(&["a2"]), 1, Overflow::Summarise);
+        queue.push(
+            "#a",
+            Some("z"),
+            lines(&["a3", "a4"]),
+            1,
+            Overflow::Summarise,
+        );
+        queue.push("#a", Some("y"), lines(&["a5"]), 1, Overflow::Summarise);
+        assert_eq!(queue.depth(), 2);
+        let sent = drain(&mut queue)
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sent,
+            vec!["a1", "(3 messages from y, z skipped to avoid flooding)"]
+        );
+    }
+}
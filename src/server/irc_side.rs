@@ -1,82 +1,622 @@
-use crate::config::Config;
+use crate::{
+    config::{Config, FloodControl, IrcNetwork},
+    log_err,
+    server::{
+        backoff::{Backoff, STABLE_CONNECTION},
+        channel_events::{batch_events, Members},
+        irc_auth::Authenticator,
+        irc_queue::{SendQueue, TokenBucket},
+        irc_split,
+        message::{ChannelEvent, Endpoint, MessageKind, RelayEvent, RelayMessage},
+        paste,
+        store::{Entry, MessageStore},
+    },
+};
 use failure::{format_err, Error, Fallible};
 use futures::{
-    future::{err, Either, Future},
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    future::{err, Future},
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot::channel,
+    },
     Stream,
 };
 use irc::{
     client::{data::config::Config as IrcConfig, ext::ClientExt, Client, IrcClient},
-    proto::{command::Command, response::Response},
+    proto::command::Command,
+};
+use log::{info, warn};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::{sleep, spawn},
+    time::{Duration, Instant},
 };
-use std::{collections::HashSet, sync::Arc};
-use unicode_segmentation::UnicodeSegmentation;
 
-/// Starts listening for IRC messages, communicating over the given channels.
+/// How often to log how many lines are waiting to be sent, while any are.
+const DEPTH_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message to be sent to an IRC channel.
+pub struct Outgoing {
+    /// The name of the network the channel is on.
+    pub network: String,
+
+    /// The name of the channel.
+    pub channel: String,
+
+    /// The text to send. Each line is sent as a separate message.
+    pub text: Arc<String>,
+
+    /// The text each line starts with, such as the sender's name, which is repeated when a line
+    /// has to be split.
+    pub prefix: Option<String>,
+
+    /// The most lines to send, as the binding allows. Longer text is pasted, and replaced with its
+    /// first line and a link.
+    pub max_lines: Option<usize>,
+
+    /// The Discord message being relayed, if the text corresponds to one.
+    pub source: Option<Arc<RelayMessage>>,
+}
+
+/// The client, if connected, and the messages waiting for it to connect. These share a lock so a
+/// message can't be queued just after the queue is sent and left there until the next reconnect.
+#[derive(Default)]
+struct Link {
+    /// The client, once it is connected and has joined its channels.
+    client: Option<IrcClient>,
+
+    /// The messages waiting to be sent once the connection comes back.
+    queue: VecDeque<Outgoing>,
+}
+
+/// The state of the IRC connection, shared between the thread that maintains it and the future
+/// that sends messages over it.
+struct Connection {
+    /// The name of the network.
+    network: String,
+
+    /// The client and the messages waiting for it.
+    link: Mutex<Link>,
+
+    /// The settings the client connected with.
+    settings: Mutex<Option<IrcNetwork>>,
+
+    /// The `!user@host` the server shows after our nickname, once we've seen it.
+    user_host: Mutex<Option<String>>,
+
+    /// Whether the connection was closed to apply new settings, so should be reopened at once.
+    reconnecting: AtomicBool,
+
+    /// The lines waiting for their turn to be sent, under flood control.
+    lines: Mutex<SendQueue>,
+
+    /// Signalled when lines are queued, or the connection comes back.
+    lines_ready: Condvar,
+
+    /// Whether enough lines are waiting that we've warned about it.
+    flooding: AtomicBool,
+}
+
+impl Connection {
+    fn new(network: String) -> Connection {
+        Connection {
+            network,
+            link: Mutex::new(Link::default()),
+            settings: Mutex::new(None),
+            user_host: Mutex::new(None),
+            reconnecting: AtomicBool::new(false),
+            lines: Mutex::new(SendQueue::default()),
+            lines_ready: Condvar::new(),
+            flooding: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns the client, if it is connected.
+    fn client(&self) -> Option<IrcClient> {
+        self.link.lock().unwrap().client.clone()
+    }
+
+    /// Queues a message's lines to be sent if connected, or queues the message until the
+    /// connection comes back if not.
+    fn send(&self, msg: Outgoing) {
+        let client = {
+            let mut link = self.link.lock().unwrap();
+            match link.client.clone() {
+                Some(client) => client,
+                None => return self.enqueue(&mut link.queue, msg),
+            }
+        };
+        self.send_with(&client, msg);
+    }
+
+    /// Queues a message's lines to be sent with the given client.
+    fn send_with(&self, client: &IrcClient, msg: Outgoing) {
+        let user_host = self.user_host.lock().unwrap().clone();
+        let lines = split_outgoing(client, &msg, user_host.as_ref().map(String::as_str));
+        let flood = Config::flood_control(&self.network);
+        let sender = msg.source.as_ref().map(|source| source.sender.as_str());
+        let (queued, depth) = {
+            let mut queue = self.lines.lock().unwrap();
+            let queued = queue.push(&msg.channel, sender, lines, flood.max_depth, flood.overflow);
+            self.lines_ready.notify_all();
+            (queued, queue.depth())
+        };
+        self.check_depth(depth, &flood);
+
+        if !queued {
+            warn!(
+                "{} can't keep up, so a message to {} was skipped",
+                self.network, msg.channel
+            );
+        } else if let Some(ref source) = msg.source {
+            record_relayed(&msg.network, &msg.channel, source);
+        }
+    }
+
+    /// Blocks until there are lines to send and the connection is up.
+    fn wait_for_lines(&self) {
+        let mut lines = self.lines.lock().unwrap();
+        while lines.depth() == 0 || self.client().is_none() {
+            lines = self.lines_ready.wait(lines).unwrap();
+        }
+    }
+
+    /// Takes the next line to send, along with the client to send it with, the channel to send it
+    /// to, and how many lines are still waiting.
+    fn next_line(&self) -> Option<(IrcClient, String, String, usize)> {
+        let mut lines = self.lines.lock().unwrap();
+        let client = self.client()?;
+        let (channel, line) = lines.pop()?;
+        Some((client, channel, line, lines.depth()))
+    }
+
+    /// Warns when the number of lines waiting to be sent grows past the threshold, and notes when
+    /// it has fallen back well below it.
+    fn check_depth(&self, depth: usize, flood: &FloodControl) {
+        if depth >= flood.warn_depth {
+            if !self.flooding.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "{} lines are waiting to be sent to {}, which is sending slower than \
+                     messages arrive",
+                    depth, self.network
+                );
+            }
+        } else if depth <= flood.warn_depth / 2 && self.flooding.swap(false, Ordering::SeqCst) {
+            info!(
+                "The lines waiting to be sent to {} have caught up",
+                self.network
+            );
+        }
+    }
+
+    /// Adds a message to the queue, dropping the oldest message if it is full.
+    fn enqueue(&self, queue: &mut VecDeque<Outgoing>, msg: Outgoing) {
+        if queue.len() >= Config::max_queued_messages() {
+            warn!(
+                "{} is disconnected and the queue is full, dropping a message",
+                self.network
+            );
+            queue.pop_front();
+        }
+        queue.push_back(msg);
+    }
+
+    /// Marks the connection as up with the given settings, and sends any queued messages.
+    /// Messages that arrive while the queue is being sent are queued behind it, and the client is
+    /// only made available once the queue is empty, so messages are still sent in order.
+    fn connected(&self, client: IrcClient, settings: IrcNetwork) {
+        *self.settings.lock().unwrap() = Some(settings);
+        loop {
+            let queued = {
+                let mut link = self.link.lock().unwrap();
+                if link.queue.is_empty() {
+                    link.client = Some(client);
+                    break;
+                }
+                link.queue.drain(..).collect::<Vec<_>>()
+            };
+            info!(
+                "Sending {} messages queued while {} was down",
+                queued.len(),
+                self.network
+            );
+            for msg in queued {
+                self.send_with(&client, msg);
+            }
+        }
+        let _lines = self.lines.lock().unwrap();
+        self.lines_ready.notify_all();
+    }
+
+    /// Marks the connection as down.
+    fn disconnected(&self) {
+        self.link.lock().unwrap().client = None;
+        *self.settings.lock().unwrap() = None;
+        *self.user_host.lock().unwrap() = None;
+    }
+
+    /// Applies a reloaded config to the connection. Changes to the nickname are applied live,
+    /// while other changes to how to connect cause a reconnect.
+    fn reload(&self) -> Fallible<()> {
+        let (client, old) = match (self.client(), self.settings()) {
+            (Some(client), Some(settings)) => (client, settings),
+            // The new settings will be used when the connection comes back.
+            _ => return Ok(()),
+        };
+        let new = match network_config(&self.network) {
+            Ok(new) => new,
+            Err(_) => {
+                warn!(
+                    "{} was removed from the config, but stays connected until Janus restarts",
+                    self.network
+                );
+                return Ok(());
+            }
+        };
+
+        if needs_reconnect(&old.config, &new.config) || old.auth != new.auth {
+            info!("{} settings changed, reconnecting", self.network);
+            self.reconnecting.store(true, Ordering::SeqCst);
+            client.send_quit("Reconnecting to apply new settings")?;
+            return Ok(());
+        }
+
+        if new.config.nickname != old.config.nickname {
+            if let Some(nickname) = new.config.nickname.clone() {
+                info!("Changing nickname on {} to {}", self.network, nickname);
+                client.send(Command::NICK(nickname))?;
+            }
+        }
+        *self.settings.lock().unwrap() = Some(new);
+        ensure_joined(&self.network, &client)?;
+        leave_unbound(&self.network, &client)
+    }
+
+    /// Returns the settings the client connected with, if it is connected.
+    fn settings(&self) -> Option<IrcNetwork> {
+        self.settings.lock().unwrap().clone()
+    }
+}
+
+/// Starts listening for IRC messages on the named network, communicating over the given channels.
+/// The connection is re-established if it drops, and messages from Discord are queued until it
+/// comes back.
 pub fn start_irc(
-    config: IrcConfig,
-    irc_send: UnboundedSender<(String, String, String)>,
-    irc_recv: UnboundedReceiver<(String, Arc<String>)>,
+    network: String,
+    irc_send: UnboundedSender<RelayEvent>,
+    irc_recv: UnboundedReceiver<Outgoing>,
 ) -> impl Future<Item = (), Error = Error> {
-    match IrcClient::from_config(config) {
-        Ok(client) => {
-            if let Err(e) = client.identify() {
-                return Either::B(err(Error::from(e)));
+    let conn = Arc::new(Connection::new(network));
+
+    let (end_send, end_recv) = channel();
+    let thread_conn = conn.clone();
+    spawn(move || {
+        let err = maintain_connection(&thread_conn, irc_send);
+        let _ = end_send.send(err);
+    });
+
+    let lines_conn = conn.clone();
+    spawn(move || send_lines(&lines_conn));
+
+    let depth_conn = conn.clone();
+    spawn(move || log_depth(&depth_conn));
+
+    // Pasting long messages can block on a paste service, so it's done on a thread of its own
+    // rather than holding up the relay.
+    let (paste_send, paste_recv) = std::sync::mpsc::channel();
+    let send_conn = conn.clone();
+    spawn(move || paste_long_messages(&paste_recv, &send_conn));
+    let send_fut = irc_recv.map_err(|()| unreachable!()).for_each(move |msg| {
+        paste_send
+            .send(msg)
+            .map_err(|_| format_err!("IRC paste thread stopped"))
+    });
+    let update = Config::notify_on_reload()
+        .map_err(|()| unreachable!())
+        .for_each(move |()| {
+            // Failing to apply a reload shouldn't take the relay down with it.
+            if let Err(err) = conn.reload() {
+                log_err(
+                    err.context(format!("Couldn't apply the new config to {}", conn.network))
+                        .into(),
+                );
+            }
+            Ok(())
+        });
+    let end = end_recv
+        .map_err(|_| format_err!("IRC client thread panicked!"))
+        .and_then(err);
+
+    update
+        .join(send_fut)
+        .map(|((), ())| ())
+        .select(end)
+        .map(|((), _)| ())
+        .map_err(|(e, _)| e)
+}
+
+/// Shortens messages with more lines than their binding allows, then sends them. Text already
+/// shortened for the previous message, which is the same message to another channel, reuses the
+/// same paste. If pasting fails, the extra lines are left out instead.
+fn paste_long_messages(recv: &std::sync::mpsc::Receiver<Outgoing>, conn: &Connection) {
+    let mut last: Option<(Arc<String>, usize, Arc<String>)> = None;
+    for mut msg in recv {
+        let (max_lines, config) = match (msg.max_lines, Config::paste()) {
+            (Some(max_lines), Some(config)) => (max_lines, config),
+            _ => {
+                conn.send(msg);
+                continue;
             }
+        };
 
-            let recv_client = client.clone();
-            let recv_fut = client.stream().map_err(Error::from).for_each(move |msg| {
-                match (msg.source_nickname(), &msg.command) {
-                    (Some(sender), Command::PRIVMSG(chan, msg)) => irc_send
-                        .unbounded_send((chan.to_string(), sender.to_string(), msg.to_string()))
-                        .map_err(|_| format_err!("Couldn't send an IRC message")),
-                    (_, Command::Response(Response::RPL_ENDOFMOTD, _, _)) => {
-                        ensure_joined(&recv_client)
+        msg.text = match &last {
+            Some((text, lines, limited)) if *text == msg.text && *lines == max_lines => {
+                limited.clone()
+            }
+            _ => {
+                let store = paste::store(&config);
+                let prefix = msg.prefix.as_ref().map_or("", String::as_str);
+                let limited = match paste::limit_lines(&*store, &msg.text, prefix, max_lines) {
+                    Ok(limited) => Arc::new(limited),
+                    Err(e) => {
+                        warn!("Couldn't paste a long message: {}", e);
+                        Arc::new(paste::cut_lines(&msg.text, prefix, max_lines))
                     }
-                    _ => Ok(()),
+                };
+                last = Some((msg.text.clone(), max_lines, limited.clone()));
+                limited
+            }
+        };
+        conn.send(msg);
+    }
+}
+
+/// Sends queued lines whenever the connection is up, as fast as flood control allows.
+fn send_lines(conn: &Connection) {
+    let mut bucket = TokenBucket::new(Config::flood_control(&conn.network).burst);
+    loop {
+        conn.wait_for_lines();
+        let flood = Config::flood_control(&conn.network);
+        if let Some(wait) = bucket.take(flood.burst, flood.rate, Instant::now()) {
+            sleep(wait);
+            continue;
+        }
+
+        if let Some((client, channel, line, depth)) = conn.next_line() {
+            conn.check_depth(depth, &flood);
+            if let Err(err) = client.send_privmsg(&channel, &line) {
+                log_err(err.into());
+            }
+        }
+    }
+}
+
+/// Periodically logs how many lines are waiting to be sent and how many messages are held until
+/// the connection comes back, while there are any.
+fn log_depth(conn: &Connection) {
+    loop {
+        sleep(DEPTH_LOG_INTERVAL);
+        let depth = conn.lines.lock().unwrap().depth();
+        let held = conn.link.lock().unwrap().queue.len();
+        if depth > 0 || held > 0 {
+            info!(
+                "{}: {} lines waiting to be sent, {} messages held until reconnected",
+                conn.network, depth, held
+            );
+        }
+    }
+}
+
+/// Keeps the IRC connection up, reconnecting with backoff whenever it drops. Only returns if
+/// events can no longer be relayed.
+fn maintain_connection(conn: &Connection, irc_send: UnboundedSender<RelayEvent>) -> Error {
+    let (event_send, event_recv) = std::sync::mpsc::channel();
+    let batch_send = irc_send.clone();
+    spawn(move || batch_events(event_recv, batch_send));
+
+    let mut backoff = Backoff::default();
+    loop {
+        let started = Instant::now();
+        let result = run_connection(conn, &irc_send, &event_send);
+        conn.disconnected();
+        match result {
+            Ok(()) => warn!("Connection to {} closed", conn.network),
+            Err(e) => {
+                if irc_send.is_closed() {
+                    return e;
                 }
-            });
-            let send_client = client.clone();
-            let send_fut = irc_recv
-                .map_err(|()| unreachable!())
-                .for_each(move |(chan, msg)| {
-                    for mut msg in msg.split('\n') {
-                        while !msg.is_empty() {
-                            let n = msg
-                                .grapheme_indices(true)
-                                .map(|(n, s)| n + s.len())
-                                .take_while(|&n| n < 400)
-                                .last()
-                                .unwrap();
-                            println!("{:?}", (n, msg));
-                            send_client
-                                .send_privmsg(chan.clone(), &msg[..n])
-                                .map_err(Error::from)?;
-                            msg = &msg[n..];
-                        }
-                    }
-                    Ok(())
-                });
-            let update = Config::notify_on_reload()
-                .map_err(|()| unreachable!())
-                .for_each(move |()| ensure_joined(&client));
-            Either::A(update.join3(recv_fut, send_fut).map(|((), (), ())| ()))
+                log_err(e);
+            }
+        }
+
+        if conn.reconnecting.swap(false, Ordering::SeqCst) {
+            backoff.reset();
+            continue;
+        }
+        if started.elapsed() >= STABLE_CONNECTION {
+            backoff.reset();
         }
-        Err(e) => Either::B(err(Error::from(e))),
+        let delay = backoff.next_delay();
+        warn!("Reconnecting to {} in {}s", conn.network, delay.as_secs());
+        sleep(delay);
+    }
+}
+
+/// Connects to IRC and relays messages until the connection drops.
+fn run_connection(
+    conn: &Connection,
+    irc_send: &UnboundedSender<RelayEvent>,
+    event_send: &std::sync::mpsc::Sender<ChannelEvent>,
+) -> Fallible<()> {
+    let settings = network_config(&conn.network)?;
+    let mut config = settings.config.clone();
+    let mut auth = Authenticator::new(settings.auth.clone());
+    auth.configure(&mut config);
+
+    let client = IrcClient::from_config(config)?;
+    auth.register(&client)?;
+    info!("Connected to {}", conn.network);
+
+    let mut members = Members::new(conn.network.clone());
+    let recv_client = client.clone();
+    client
+        .stream()
+        .map_err(Error::from)
+        .for_each(move |msg| {
+            if auth.handle(&recv_client, &msg)? {
+                ensure_joined(&conn.network, &recv_client)?;
+                conn.connected(recv_client.clone(), settings.clone());
+            }
+            if let (Command::JOIN(..), Some(prefix)) = (&msg.command, &msg.prefix) {
+                if msg.source_nickname() == Some(recv_client.current_nickname()) {
+                    if let Some(at) = prefix.find('!') {
+                        *conn.user_host.lock().unwrap() = Some(prefix[at..].to_string());
+                    }
+                }
+            }
+            for event in members.handle(recv_client.current_nickname(), &msg) {
+                event_send
+                    .send(event)
+                    .map_err(|_| format_err!("Channel event batcher stopped"))?;
+            }
+
+            let relay = |kind, chan: &str, sender: &str, content: &str| {
+                irc_send
+                    .unbounded_send(RelayEvent::Message(RelayMessage {
+                        origin: Endpoint::Irc(conn.network.clone(), chan.to_string()),
+                        id: None,
+                        sender: sender.to_string(),
+                        nickname: None,
+                        kind,
+                        content: content.to_string(),
+                        attachments: Vec::new(),
+                    }))
+                    .map_err(|_| format_err!("Couldn't send an IRC message"))
+            };
+            match (msg.source_nickname(), &msg.command) {
+                (Some(sender), Command::PRIVMSG(chan, text)) => match parse_ctcp(text) {
+                    Some(("ACTION", action)) => relay(MessageKind::Action, chan, sender, action),
+                    Some(_) => Ok(()),
+                    None => relay(MessageKind::Normal, chan, sender, text),
+                },
+                (Some(sender), Command::NOTICE(chan, text)) if parse_ctcp(text).is_none() => {
+                    relay(MessageKind::Notice, chan, sender, text)
+                }
+                _ => Ok(()),
+            }
+        })
+        .wait()
+}
+
+/// Returns the config to connect to the named network with.
+fn network_config(network: &str) -> Fallible<IrcNetwork> {
+    let mut settings = Config::irc_network(network)
+        .ok_or_else(|| format_err!("{} is no longer in the config", network))?;
+    // CTCP requests other than ACTION are answered by the irc crate, using this for VERSION.
+    if settings.config.version.is_none() {
+        settings.config.version = Some(format!("janus {}", env!("CARGO_PKG_VERSION")));
+    }
+    Ok(settings)
+}
+
+/// Returns whether two IRC configs differ in a way that needs a reconnect to take effect. The
+/// nickname and channels can be changed without one, so are left out of the comparison.
+fn needs_reconnect(old: &IrcConfig, new: &IrcConfig) -> bool {
+    let mut new = new.clone();
+    new.nickname = old.nickname.clone();
+    new.channels = old.channels.clone();
+    *old != new
+}
+
+/// Splits a message into the lines to send to its IRC channel, so each fits once the server adds
+/// our `!user@host` to it, if known.
+fn split_outgoing(client: &IrcClient, out: &Outgoing, user_host: Option<&str>) -> Vec<String> {
+    let max_len = irc_split::max_payload(client.current_nickname(), user_host, &out.channel);
+    let prefix = out.prefix.as_ref().map_or("", String::as_str);
+    out.text
+        .split('\n')
+        .filter(|line| !line.is_empty())
+        .flat_map(|line| irc_split::split_line(line, max_len, prefix))
+        .collect()
+}
+
+/// Splits a CTCP message into its command and arguments, or returns `None` if the message isn't
+/// one.
+fn parse_ctcp(text: &str) -> Option<(&str, &str)> {
+    if !text.starts_with('\x01') {
+        return None;
     }
+    let text = text[1..].trim_end_matches('\x01');
+    let mut parts = text.splitn(2, ' ');
+    let command = parts.next().unwrap_or("");
+    Some((command, parts.next().unwrap_or("")))
 }
 
-fn ensure_joined(client: &impl ClientExt) -> Fallible<()> {
-    let current_channels: HashSet<String> = client
+/// Records a Discord message that was relayed to an IRC channel in the message store.
+fn record_relayed(irc_network: &str, irc_channel: &str, msg: &RelayMessage) {
+    if let (Endpoint::Discord(discord_channel), Some(id)) = (&msg.origin, msg.id) {
+        MessageStore::record(Entry {
+            discord_channel: *discord_channel,
+            discord_message: id,
+            irc_network: irc_network.to_string(),
+            irc_channel: irc_channel.to_string(),
+            sender: msg.sender.clone(),
+            from_irc: false,
+            content: msg.content.clone(),
+            timestamp: Entry::now(),
+        });
+    }
+}
+
+/// Joins the channels that are bound or listed in the network's config, other than those already
+/// joined.
+fn ensure_joined(network: &str, client: &impl ClientExt) -> Fallible<()> {
+    let mut current_channels: HashSet<String> = client
         .list_channels()
         .map(|chans| chans.into_iter().collect())
         .unwrap_or_default();
-    let chans_to_join = Config::irc_channels()
+    let (listed, keys) = match Config::irc_network(network) {
+        Some(network) => (network.config.channels, network.config.channel_keys),
+        None => (None, None),
+    };
+    let keys = keys.unwrap_or_default();
+    let chans_to_join = Config::irc_channels(network)
         .into_iter()
-        .filter(|chan| !current_channels.contains(chan));
+        .chain(listed.unwrap_or_default());
 
     for chan in chans_to_join {
-        client.send_join(chan)?;
+        if current_channels.insert(chan.clone()) {
+            let key = keys.get(&chan).cloned();
+            client.send(Command::JOIN(chan, key, None))?;
+        }
+    }
+    Ok(())
+}
+
+/// Leaves the channels that are no longer bound to anything, other than those the config says to
+/// stay in.
+fn leave_unbound(network: &str, client: &impl ClientExt) -> Fallible<()> {
+    let network_channels = Config::irc_network(network).and_then(|n| n.config.channels);
+    let keep: HashSet<String> = Config::irc_channels(network)
+        .into_iter()
+        .chain(Config::keep_channels(network))
+        .chain(network_channels.unwrap_or_default())
+        .map(|chan| chan.to_lowercase())
+        .collect();
+    let chans_to_leave = client
+        .list_channels()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|chan| !keep.contains(&chan.to_lowercase()));
+
+    let part_message = Config::part_message();
+    for chan in chans_to_leave {
+        info!("Leaving {} on {}, which is no longer bound", chan, network);
+        client.send(Command::PART(chan, part_message.clone()))?;
     }
     Ok(())
 }
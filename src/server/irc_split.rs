@@ -0,0 +1,203 @@
+//! Splitting text into lines that fit in IRC messages.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// The most bytes an IRC message may have, including the trailing CRLF.
+const MAX_MESSAGE_LEN: usize = 512;
+
+/// The longest `!user@host` part of a source to assume when the real one isn't known, from the
+/// usual limits of 10 bytes for usernames and 63 bytes for hostnames.
+const MAX_USER_HOST_LEN: usize = 1 + 10 + 1 + 63;
+
+/// Returns how many bytes of text fit in a PRIVMSG to the target, once the server adds our source
+/// to it when relaying it to others. The source is `!user@host` following our nick, if it is
+/// known.
+pub fn max_payload(nick: &str, user_host: Option<&str>, target: &str) -> usize {
+    let user_host_len = user_host.map_or(MAX_USER_HOST_LEN, str::len);
+    // The message is relayed as ":<nick><user_host> PRIVMSG <target> :<text>\r\n".
+    let overhead = 1 + nick.len() + user_host_len + " PRIVMSG ".len() + target.len() + 2 + 2;
+    MAX_MESSAGE_LEN.saturating_sub(overhead)
+}
+
+/// Splits a line into pieces of at most `max_len` bytes, preferring to split between words and
+/// never splitting a grapheme cluster unless it is longer than `max_len` by itself. If the line
+/// starts with `prefix`, such as the name of the sender, every piece does.
+///
+/// Pieces can only be longer than `max_len` if it is too short for a single character.
+pub fn split_line(line: &str, max_len: usize, prefix: &str) -> Vec<String> {
+    if line.len() <= max_len {
+        return vec![line.to_string()];
+    }
+
+    // The prefix is only repeated if it leaves room for a reasonable amount of text.
+    let prefix = if !prefix.is_empty() && line.starts_with(prefix) && prefix.len() <= max_len / 2 {
+        prefix
+    } else {
+        ""
+    };
+    let budget = max_len - prefix.len();
+
+    let mut pieces = Vec::new();
+    let mut rest = &line[prefix.len()..];
+    while !rest.is_empty() {
+        let end = split_point(rest, budget);
+        pieces.push(format!("{}{}", prefix, &rest[..end]));
+        rest = rest[end..].trim_start();
+    }
+    pieces
+}
+
+/// Returns where to split off the first piece of some text, so the piece has at most `max_len`
+/// bytes if possible. The piece is never empty.
+fn split_point(text: &str, max_len: usize) -> usize {
+    if text.len() <= max_len {
+        return text.len();
+    }
+
+    let end = text
+        .grapheme_indices(true)
+        .map(|(i, g)| i + g.len())
+        .take_while(|&end| end <= max_len)
+        .last()
+        // A single grapheme cluster is too long, so split it between characters instead.
+        .or_else(|| {
+            text.char_indices()
+                .map(|(i, c)| i + c.len_utf8())
+                .take_while(|&end| end <= max_len)
+                .last()
+        })
+        // Not even a single character fits, so send it anyway.
+        .unwrap_or_else(|| text.chars().next().map_or(0, char::len_utf8));
+
+    if text[end..].starts_with(char::is_whitespace) {
+        return end;
+    }
+    match text[..end].rfind(char::is_whitespace) {
+        Some(space) if space > 0 => space,
+        _ => end,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{prop_assert, prop_assert_eq, proptest};
+
+    #[test]
+    fn short_lines_are_unchanged() {
+        assert_eq!(split_line("hello world", 20, ""), vec!["hello world"]);
+        assert_eq!(split_line("", 20, ""), vec![""]);
+    }
+
+    #[test]
+    fn splits_between_words() {
+        assert_eq!(
+            split_line("the quick brown fox jumps", 12, ""),
+            vec!["the quick", "brown fox", "jumps"]
+        );
+    }
+
+    #[test]
+    fn splits_long_words() {
+        assert_eq!(
+            split_line("abcdefghij klm", 4, ""),
+            vec!["abcd", "efgh", "ij", "klm"]
+        );
+    }
+
+    #[test]
+    fn repeats_the_prefix() {
+        assert_eq!(
+            split_line("nick: one two three four", 14, "nick: "),
+            vec!["nick: one two", "nick: three", "nick: four"]
+        );
+    }
+
+    #[test]
+    fn ignores_a_prefix_the_line_doesnt_start_with() {
+        assert_eq!(
+            split_line("one two three", 8, "nick: "),
+            vec!["one two", "three"]
+        );
+    }
+
+    #[test]
+    fn doesnt_split_grapheme_clusters() {
+        let family = "\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}";
+        let line = format!("{}{}", family, family);
+        assert_eq!(
+            split_line(&line, family.len() + 4, ""),
+            vec![family, family]
+        );
+    }
+
+    #[test]
+    fn splits_grapheme_clusters_longer_than_the_limit() {
+        let zalgo = format!("a{}", "\u{0301}".repeat(300));
+        let pieces = split_line(&zalgo, 100, "");
+        assert!(pieces.iter().all(|piece| piece.len() <= 100));
+        assert_eq!(pieces.concat(), zalgo);
+    }
+
+    #[test]
+    fn makes_progress_with_tiny_limits() {
+        assert_eq!(
+            split_line("\u{1F600}\u{1F600}", 2, ""),
+            vec!["\u{1F600}", "\u{1F600}"]
+        );
+    }
+
+    #[test]
+    fn max_payload_accounts_for_the_source_and_target() {
+        let overhead = ":janus!~janus@example.com PRIVMSG #janus :\r\n".len();
+        assert_eq!(
+            max_payload("janus", Some("!~janus@example.com"), "#janus"),
+            512 - overhead
+        );
+        assert!(max_payload("janus", None, "#janus") < 512 - overhead);
+    }
+
+    /// Removes all whitespace from a string, since splitting may drop some.
+    fn without_whitespace(text: &str) -> String {
+        text.chars().filter(|c| !c.is_whitespace()).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn pieces_fit(line in "\\PC{0,300}", max_len in 4usize..100) {
+            for piece in split_line(&line, max_len, "") {
+                prop_assert!(piece.len() <= max_len, "{:?} is too long", piece);
+            }
+        }
+
+        #[test]
+        fn pieces_arent_empty(line in "\\PC{1,300}", max_len in 4usize..100) {
+            for piece in split_line(&line, max_len, "") {
+                prop_assert!(!piece.is_empty());
+            }
+        }
+
+        #[test]
+        fn only_whitespace_is_lost(line in "\\PC{0,300}", max_len in 4usize..100) {
+            let pieces = split_line(&line, max_len, "");
+            prop_assert_eq!(without_whitespace(&pieces.concat()), without_whitespace(&line));
+        }
+
+        #[test]
+        fn prefix_is_repeated(
+            name in "[a-z]{1,10}",
+            text in "\\PC{0,300}",
+            max_len in 30usize..100,
+        ) {
+            let prefix = format!("{}: ", name);
+            let line = format!("{}{}", prefix, text);
+            let pieces = split_line(&line, max_len, &prefix);
+            for piece in &pieces {
+                prop_assert!(piece.starts_with(&prefix));
+                prop_assert!(piece.len() <= max_len);
+            }
+            let text_again = pieces.iter().map(|p| &p[prefix.len()..]).collect::<String>();
+            prop_assert_eq!(without_whitespace(&text_again), without_whitespace(&text));
+        }
+    }
+}
@@ -0,0 +1,143 @@
+//! The messages passed between the two sides of the relay.
+
+pub use crate::config::Endpoint;
+
+use crate::config::{EventKind, SenderName};
+
+/// Something that happened on one side of the relay, which may need to be sent to the other.
+#[derive(Clone, Debug)]
+pub enum RelayEvent {
+    /// A message was sent.
+    Message(RelayMessage),
+
+    /// A message was edited.
+    Edit(RelayEdit),
+
+    /// A message was deleted.
+    Delete(RelayMessage),
+
+    /// Users joined or left a channel, or its topic was changed.
+    Channel(ChannelEvent),
+}
+
+impl RelayEvent {
+    /// Returns the channel the event occurred in.
+    pub fn origin(&self) -> &Endpoint {
+        match self {
+            RelayEvent::Message(msg) | RelayEvent::Delete(msg) => &msg.origin,
+            RelayEvent::Edit(edit) => &edit.new.origin,
+            RelayEvent::Channel(event) => &event.origin,
+        }
+    }
+}
+
+/// A message sent on one side of the relay.
+#[derive(Clone, Debug)]
+pub struct RelayMessage {
+    /// The channel the message was sent to.
+    pub origin: Endpoint,
+
+    /// The ID of the message, if the side it came from has them.
+    pub id: Option<u64>,
+
+    /// The account name of the sender.
+    pub sender: String,
+
+    /// The sender's nickname in the guild the message was sent in, if they have one.
+    pub nickname: Option<String>,
+
+    /// What sort of message this is.
+    pub kind: MessageKind,
+
+    /// The text of the message.
+    pub content: String,
+
+    /// The URLs of any files attached to the message.
+    pub attachments: Vec<String>,
+}
+
+/// The different sorts of messages.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageKind {
+    /// An ordinary message.
+    Normal,
+
+    /// An action, as sent with `/me`.
+    Action,
+
+    /// An IRC notice.
+    Notice,
+}
+
+impl RelayMessage {
+    /// Returns the name to show for the sender.
+    pub fn sender_name(&self, which: SenderName) -> &str {
+        match (which, &self.nickname) {
+            (SenderName::Nickname, Some(nickname)) => nickname,
+            _ => &self.sender,
+        }
+    }
+}
+
+/// An edit to a message.
+#[derive(Clone, Debug)]
+pub struct RelayEdit {
+    /// The text of the message before the edit, if it is known.
+    pub old_content: Option<String>,
+
+    /// The message after the edit.
+    pub new: RelayMessage,
+}
+
+/// A change to the users in a channel, or to its topic. Events of the same kind that happen close
+/// together are batched into one.
+#[derive(Clone, Debug)]
+pub struct ChannelEvent {
+    /// The channel the event occurred in.
+    pub origin: Endpoint,
+
+    /// The nicks of the users the event happened to.
+    pub nicks: Vec<String>,
+
+    /// What happened.
+    pub kind: ChannelEventKind,
+}
+
+/// The different sorts of channel events.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChannelEventKind {
+    /// The users joined the channel.
+    Join,
+
+    /// The users left the channel, with the given reason.
+    Part(Option<String>),
+
+    /// The users disconnected, with the given reason.
+    Quit(Option<String>),
+
+    /// The users disconnected because of a netsplit between the given servers.
+    Netsplit(String),
+
+    /// The users were kicked by the given user, with the given reason.
+    Kick(String, Option<String>),
+
+    /// The user changed their nick to the given one.
+    Nick(String),
+
+    /// The user changed the topic to the given one.
+    Topic(String),
+}
+
+impl ChannelEventKind {
+    /// Returns the setting that controls whether events of this kind are relayed.
+    pub fn setting(&self) -> EventKind {
+        match self {
+            ChannelEventKind::Join => EventKind::Join,
+            ChannelEventKind::Part(_) => EventKind::Part,
+            ChannelEventKind::Quit(_) | ChannelEventKind::Netsplit(_) => EventKind::Quit,
+            ChannelEventKind::Kick(_, _) => EventKind::Kick,
+            ChannelEventKind::Nick(_) => EventKind::Nick,
+            ChannelEventKind::Topic(_) => EventKind::Topic,
+        }
+    }
+}
@@ -1,58 +1,438 @@
+mod backoff;
+mod channel_events;
+mod dead_letters;
 mod discord_parser;
 mod discord_side;
+mod discord_split;
+mod formatting;
+mod irc_auth;
+mod irc_parser;
+mod irc_queue;
 mod irc_side;
+mod irc_split;
+mod message;
+mod paste;
+mod store;
 
-use self::{discord_side::start_discord, irc_side::start_irc};
-use crate::config::Config;
+use self::{
+    discord_side::start_discord,
+    irc_side::start_irc,
+    message::{
+        ChannelEvent, ChannelEventKind, Endpoint, MessageKind, RelayEdit, RelayEvent, RelayMessage,
+    },
+    store::MessageStore,
+};
+use crate::config::{Binding, Config, SenderName};
 use failure::{format_err, Error};
 use futures::{
-    stream::{iter_ok, Stream},
+    future::{err, join_all, Either},
+    stream::Stream,
     sync::mpsc::unbounded,
-    Future, Sink,
+    Future,
 };
-use std::sync::Arc;
+use log::warn;
+use std::{collections::HashMap, sync::Arc};
+
+/// The number of characters of a deleted message to show on IRC.
+const DELETED_EXCERPT_LEN: usize = 80;
 
 pub fn run(discord_token: &str) -> impl Future<Item = (), Error = Error> {
+    if let Err(e) = MessageStore::init(Config::state_dir(), Config::message_retention()) {
+        return Either::B(err(e));
+    }
+    if let Some(config) = Config::paste() {
+        if let Err(e) = paste::serve(&config) {
+            return Either::B(err(e));
+        }
+    }
+
     let (discord_send, discord_send_recv) = unbounded();
     let (discord_recv_send, discord_recv) = unbounded();
     let (irc_send, irc_send_recv) = unbounded();
-    let (irc_recv_send, irc_recv) = unbounded();
 
     let discord_side = start_discord(&discord_token, discord_send, discord_recv);
-    let irc_side = start_irc(Config::irc_config(), irc_send, irc_recv);
-    let discord_to_irc = discord_send_recv
-        .map_err(|_| format_err!("Discord hung up?"))
-        .map(|(chan, sender, msg)| {
-            let msg = Arc::new(format_discord_for_irc(sender, msg));
-            iter_ok(
-                Config::irc_for_discord(chan)
-                    .into_iter()
-                    .map(move |chan| (chan, msg.clone()))
-                    .collect::<Vec<_>>(),
-            )
-        })
-        .flatten()
-        .forward(irc_recv_send.sink_map_err(|_| format_err!("Can't send to IRC")))
-        .map(|_| ());
-    let irc_to_discord = irc_send_recv
-        .map_err(|_| format_err!("IRC hung up?"))
-        .map(|(chan, sender, msg)| {
-            let msg = Arc::new(format_irc_for_discord(sender, msg));
-            iter_ok(Config::discord_for_irc(chan)).map(move |chan| (chan, msg.clone()))
+
+    let mut irc_sides = Vec::new();
+    let mut irc_recv_sends = HashMap::new();
+    for network in Config::irc_networks() {
+        let (irc_recv_send, irc_recv) = unbounded();
+        irc_sides.push(start_irc(network.clone(), irc_send.clone(), irc_recv));
+        irc_recv_sends.insert(network, irc_recv_send);
+    }
+    let irc_side = join_all(irc_sides).map(|_| ());
+
+    let router = discord_send_recv
+        .select(irc_send_recv)
+        .map_err(|()| unreachable!())
+        .for_each(move |event| {
+            for out in route(&event) {
+                match out {
+                    Outgoing::Discord(msg) => discord_recv_send
+                        .unbounded_send(msg)
+                        .map_err(|_| format_err!("Can't send to Discord"))?,
+                    Outgoing::Irc(msg) => match irc_recv_sends.get(&msg.network) {
+                        Some(send) => send
+                            .unbounded_send(msg)
+                            .map_err(|_| format_err!("Can't send to IRC"))?,
+                        // Networks added by a reload aren't connected to until a restart.
+                        None => warn!("Not connected to {}, dropping a message", msg.network),
+                    },
+                }
+            }
+            Ok(())
+        });
+
+    Either::A(discord_side.join3(irc_side, router).map(|((), (), ())| ()))
+}
+
+/// A message to be sent to one side of the relay.
+enum Outgoing {
+    Discord(discord_side::Outgoing),
+    Irc(irc_side::Outgoing),
+}
+
+/// Returns the messages to send for an event, to every channel bound to the one it occurred in.
+fn route(event: &RelayEvent) -> Vec<Outgoing> {
+    let source = match event {
+        RelayEvent::Message(msg) => Some(msg),
+        RelayEvent::Edit(edit) => Some(&edit.new),
+        RelayEvent::Delete(_) | RelayEvent::Channel(_) => None,
+    };
+    let source = source.cloned().map(Arc::new);
+    Config::routes_from(event.origin())
+        .into_iter()
+        .filter_map(|(binding, member)| match member.endpoint {
+            Endpoint::Irc(network, channel) => format_for_irc(event, &binding).map(|text| {
+                Outgoing::Irc(irc_side::Outgoing {
+                    network,
+                    channel,
+                    text: Arc::new(text),
+                    prefix: irc_prefix(event, &binding),
+                    max_lines: binding.max_irc_lines,
+                    source: source.clone(),
+                })
+            }),
+            Endpoint::Discord(channel) => {
+                let with_sender = member.webhook.is_none();
+                format_for_discord(event, &binding, channel, with_sender).map(|content| {
+                    Outgoing::Discord(discord_side::Outgoing {
+                        channel,
+                        webhook: member.webhook,
+                        content: Arc::new(content),
+                        source: source.clone(),
+                        sender: source
+                            .as_ref()
+                            .map(|source| source.sender_name(binding.sender_name).to_string()),
+                    })
+                })
+            }
         })
-        .flatten()
-        .forward(discord_recv_send.sink_map_err(|_| format_err!("Can't send to Discord")))
-        .map(|_| ());
+        .collect()
+}
+
+/// Formats an event for an IRC channel in the given binding, or returns `None` if it shouldn't be
+/// relayed there.
+fn format_for_irc(event: &RelayEvent, binding: &Binding) -> Option<String> {
+    match event {
+        RelayEvent::Message(msg) => match msg.origin {
+            Endpoint::Discord(_) => Some(format_discord_for_irc(msg, binding.sender_name)),
+            Endpoint::Irc(_, _) if msg.kind == MessageKind::Notice && !binding.relay_notices => {
+                None
+            }
+            Endpoint::Irc(_, _) => Some(format_irc_for_irc(msg)),
+        },
+        RelayEvent::Edit(edit) => Some(format_discord_edit_for_irc(edit, binding.sender_name)),
+        RelayEvent::Delete(msg) if binding.relay_deletes => {
+            Some(format_discord_delete_for_irc(msg, binding.sender_name))
+        }
+        RelayEvent::Delete(_) => None,
+        // Channel events are only relayed to Discord, where they can be shown unobtrusively.
+        RelayEvent::Channel(_) => None,
+    }
+}
+
+/// Returns the text that `format_for_irc` starts each line of an event with, which identifies the
+/// sender.
+fn irc_prefix(event: &RelayEvent, binding: &Binding) -> Option<String> {
+    match event {
+        RelayEvent::Message(msg) => Some(match (&msg.origin, msg.kind) {
+            (Endpoint::Discord(_), _) => format!("{}: ", msg.sender_name(binding.sender_name)),
+            (Endpoint::Irc(_, _), MessageKind::Normal) => format!("{}: ", msg.sender),
+            (Endpoint::Irc(_, _), MessageKind::Action) => format!("* {} ", msg.sender),
+            (Endpoint::Irc(_, _), MessageKind::Notice) => format!("-{}- ", msg.sender),
+        }),
+        RelayEvent::Edit(edit) => Some(format!(
+            "{} (edited): ",
+            edit.new.sender_name(binding.sender_name)
+        )),
+        RelayEvent::Delete(_) | RelayEvent::Channel(_) => None,
+    }
+}
+
+/// Formats an event for a Discord channel in the given binding, or returns `None` if it shouldn't
+/// be relayed there. The sender is only included if `with_sender` is set, since messages sent
+/// through a webhook show it as the author instead.
+fn format_for_discord(
+    event: &RelayEvent,
+    binding: &Binding,
+    channel: u64,
+    with_sender: bool,
+) -> Option<String> {
+    let msg = match event {
+        RelayEvent::Message(msg) => msg,
+        RelayEvent::Channel(event) if binding.events.contains(&event.kind.setting()) => {
+            return Some(format_channel_event_for_discord(event));
+        }
+        RelayEvent::Channel(_) => return None,
+        // Relayed Discord messages aren't tracked well enough to edit or delete them along with
+        // the original, and IRC has no way to edit or delete messages.
+        RelayEvent::Edit(_) | RelayEvent::Delete(_) => return None,
+    };
+    if msg.kind == MessageKind::Notice && !binding.relay_notices {
+        return None;
+    }
+
+    let mut msg = msg.clone();
+    if binding.mentions {
+        msg.content = irc_parser::add_mentions(&msg.content, channel);
+    }
+    let content = match msg.origin {
+        Endpoint::Discord(_) => format_discord_for_discord(&msg, binding.sender_name, with_sender),
+        Endpoint::Irc(_, _) => format_irc_for_discord(&msg, with_sender),
+    };
+    // Coalescing only joins messages with newlines and splitting only cuts them, so neither can
+    // form a mention that isn't in the formatted text.
+    Some(irc_parser::neutralise_mentions(&content, binding, channel))
+}
 
-    discord_side
-        .join4(irc_side, discord_to_irc, irc_to_discord)
-        .map(|((), (), (), ())| ())
+/// Formats a Discord message for IRC. Each attachment is put on its own line.
+fn format_discord_for_irc(msg: &RelayMessage, sender_name: SenderName) -> String {
+    let sender = msg.sender_name(sender_name);
+    Some(formatting::discord_to_irc(&msg.content))
+        .filter(|content| !content.is_empty())
+        .into_iter()
+        .chain(msg.attachments.iter().cloned())
+        .map(|line| format!("{}: {}", sender, line))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
-fn format_discord_for_irc(sender: String, msg: String) -> String {
-    format!("{}: {}", sender, msg)
+/// Formats an edit to a Discord message for IRC. Short edits are shown as a substitution rather
+/// than repeating the whole message.
+fn format_discord_edit_for_irc(edit: &RelayEdit, sender_name: SenderName) -> String {
+    let new = formatting::discord_to_irc(&edit.new.content);
+    let diff = edit
+        .old_content
+        .as_ref()
+        .and_then(|old| formatting::sed_diff(&formatting::discord_to_irc(old), &new));
+    format!(
+        "{} (edited): {}",
+        edit.new.sender_name(sender_name),
+        diff.unwrap_or(new)
+    )
 }
 
-fn format_irc_for_discord(sender: String, msg: String) -> String {
-    format!("__**{}**__: {}", sender, msg)
+/// Formats the deletion of a Discord message for IRC.
+fn format_discord_delete_for_irc(msg: &RelayMessage, sender_name: SenderName) -> String {
+    let content = formatting::discord_to_irc(&msg.content);
+    let first_line = content.lines().next().unwrap_or("");
+    format!(
+        "{} (deleted): {}",
+        msg.sender_name(sender_name),
+        formatting::truncate(first_line, DELETED_EXCERPT_LEN)
+    )
+}
+
+/// Formats a Discord message for another Discord channel. Each attachment is put on its own line.
+fn format_discord_for_discord(
+    msg: &RelayMessage,
+    sender_name: SenderName,
+    with_sender: bool,
+) -> String {
+    let content = Some(msg.content.clone())
+        .filter(|content| !content.is_empty())
+        .into_iter()
+        .chain(msg.attachments.iter().cloned())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if with_sender {
+        let sender = formatting::escape_markdown(msg.sender_name(sender_name));
+        format!("__**{}**__: {}", sender, content)
+    } else {
+        content
+    }
+}
+
+/// Formats an IRC message for another IRC channel.
+fn format_irc_for_irc(msg: &RelayMessage) -> String {
+    match msg.kind {
+        MessageKind::Normal => format!("{}: {}", msg.sender, msg.content),
+        MessageKind::Action => format!("* {} {}", msg.sender, msg.content),
+        MessageKind::Notice => format!("-{}- {}", msg.sender, msg.content),
+    }
+}
+
+/// Formats an IRC message for Discord. The sender is only included if `with_sender` is set, since
+/// messages sent through a webhook show it as the author instead.
+fn format_irc_for_discord(msg: &RelayMessage, with_sender: bool) -> String {
+    let content = formatting::irc_to_discord(&msg.content);
+    let sender = formatting::escape_markdown(&msg.sender);
+    match (msg.kind, with_sender) {
+        (MessageKind::Normal, true) => format!("__**{}**__: {}", sender, content),
+        (MessageKind::Normal, false) => content,
+        (MessageKind::Action, true) => format!("_\\* {} {}_", sender, content),
+        (MessageKind::Action, false) => format!("_{}_", content),
+        (MessageKind::Notice, true) => format!("-__**{}**__- {}", sender, content),
+        (MessageKind::Notice, false) => format!("**[notice]** {}", content),
+    }
+}
+
+/// Formats an IRC channel event for Discord.
+fn format_channel_event_for_discord(event: &ChannelEvent) -> String {
+    let nicks = format_nick_list(&event.nicks);
+    let reason = |reason: &Option<String>| match reason {
+        Some(reason) if !reason.is_empty() => {
+            format!(" ({})", formatting::irc_to_discord(reason))
+        }
+        _ => String::new(),
+    };
+    let text = match &event.kind {
+        ChannelEventKind::Join => format!("{} joined", nicks),
+        ChannelEventKind::Part(why) => format!("{} left{}", nicks, reason(why)),
+        ChannelEventKind::Quit(why) => format!("{} quit{}", nicks, reason(why)),
+        ChannelEventKind::Netsplit(servers) => format!(
+            "{} quit in a netsplit ({})",
+            nicks,
+            formatting::escape_markdown(servers)
+        ),
+        ChannelEventKind::Kick(by, why) => format!(
+            "{} {} kicked by {}{}",
+            nicks,
+            if event.nicks.len() == 1 {
+                "was"
+            } else {
+                "were"
+            },
+            formatting::escape_markdown(by),
+            reason(why)
+        ),
+        ChannelEventKind::Nick(new) => format!(
+            "{} is now known as {}",
+            nicks,
+            formatting::escape_markdown(new)
+        ),
+        ChannelEventKind::Topic(topic) => format!(
+            "{} changed the topic to: {}",
+            nicks,
+            formatting::irc_to_discord(topic)
+        ),
+    };
+    format!("_{}_", text)
+}
+
+/// Formats a list of nicks like "alice, bob and carol", summarizing long lists.
+fn format_nick_list(nicks: &[String]) -> String {
+    const MAX_NICKS: usize = 5;
+
+    let mut names = nicks
+        .iter()
+        .take(MAX_NICKS)
+        .map(|nick| formatting::escape_markdown(nick))
+        .collect::<Vec<_>>();
+    if nicks.len() > MAX_NICKS {
+        names.push(format!("{} others", nicks.len() - MAX_NICKS));
+    }
+    match names.split_last() {
+        Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+        Some((last, _)) => last.clone(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicks(nicks: &[&str]) -> Vec<String> {
+        nicks.iter().map(|nick| nick.to_string()).collect()
+    }
+
+    fn channel_event(names: &[&str], kind: ChannelEventKind) -> String {
+        format_channel_event_for_discord(&ChannelEvent {
+            origin: Endpoint::Irc("net".to_string(), "#chan".to_string()),
+            nicks: nicks(names),
+            kind,
+        })
+    }
+
+    #[test]
+    fn nick_lists_read_naturally() {
+        assert_eq!(format_nick_list(&[]), "");
+        assert_eq!(format_nick_list(&nicks(&["alice"])), "alice");
+        assert_eq!(format_nick_list(&nicks(&["alice", "bob"])), "alice and bob");
+        assert_eq!(
+            format_nick_list(&nicks(&["alice", "bob", "carol"])),
+            "alice, bob and carol"
+        );
+        assert_eq!(
+            format_nick_list(&nicks(&["a", "b", "c", "d", "e", "f", "g"])),
+            "a, b, c, d, e and 2 others"
+        );
+        assert_eq!(format_nick_list(&nicks(&["snake_case"])), "snake\\_case");
+    }
+
+    #[test]
+    fn channel_events_are_described() {
+        assert_eq!(
+            channel_event(&["alice", "bob"], ChannelEventKind::Join),
+            "_alice and bob joined_"
+        );
+        assert_eq!(
+            channel_event(
+                &["alice"],
+                ChannelEventKind::Part(Some("see \x02you\x02".to_string()))
+            ),
+            "_alice left (see **you**)_"
+        );
+        assert_eq!(
+            channel_event(&["alice"], ChannelEventKind::Part(Some(String::new()))),
+            "_alice left_"
+        );
+        assert_eq!(
+            channel_event(&["alice"], ChannelEventKind::Quit(None)),
+            "_alice quit_"
+        );
+        assert_eq!(
+            channel_event(
+                &["alice", "bob"],
+                ChannelEventKind::Netsplit("irc.a.net irc.b.net".to_string())
+            ),
+            "_alice and bob quit in a netsplit (irc.a.net irc.b.net)_"
+        );
+        assert_eq!(
+            channel_event(&["bob"], ChannelEventKind::Nick("bob_away".to_string())),
+            "_bob is now known as bob\\_away_"
+        );
+        assert_eq!(
+            channel_event(&["alice"], ChannelEventKind::Topic("new topic".to_string())),
+            "_alice changed the topic to: new topic_"
+        );
+    }
+
+    #[test]
+    fn kicks_agree_with_the_number_of_nicks() {
+        let kick = || ChannelEventKind::Kick("op".to_string(), Some("spam".to_string()));
+        assert_eq!(
+            channel_event(&["bob"], kick()),
+            "_bob was kicked by op (spam)_"
+        );
+        assert_eq!(
+            channel_event(&["bob", "eve"], kick()),
+            "_bob and eve were kicked by op (spam)_"
+        );
+        assert_eq!(
+            channel_event(&["bob"], ChannelEventKind::Kick("op".to_string(), None)),
+            "_bob was kicked by op_"
+        );
+    }
 }
@@ -0,0 +1,339 @@
+//! Stores for messages too long to relay, which are replaced with a link. Pastes are either written
+//! to a directory served over HTTP, possibly by Janus itself, or uploaded to a paste service.
+
+use failure::{format_err, Fallible, ResultExt};
+use hyper::{
+    header::ContentType,
+    net::HttpsConnector,
+    server::{Listening, Request, Response, Server},
+    status::StatusCode,
+    uri::RequestUri,
+};
+use hyper_native_tls::NativeTlsClient;
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use std::{
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{config::PasteConfig, server::formatting::truncate};
+
+lazy_static! {
+    /// The server for the paste directory, which stops when dropped.
+    static ref SERVER: Mutex<Option<Listening>> = Mutex::new(None);
+}
+
+/// The length of the random names pastes are given, which keeps them from being guessed.
+const NAME_LEN: usize = 16;
+
+/// How long to wait for a paste service before giving up.
+const UPLOAD_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The number of characters of the first line to show alongside the link to a paste.
+const EXCERPT_LEN: usize = 80;
+
+/// Somewhere to put text that is too long to relay.
+pub trait PasteStore {
+    /// Stores some text, returning the URL it can be read at.
+    fn paste(&self, text: &str) -> Fallible<String>;
+}
+
+/// Returns the store the config describes.
+pub fn store(config: &PasteConfig) -> Box<dyn PasteStore> {
+    match (&config.endpoint, &config.dir, &config.url) {
+        (Some(endpoint), _, _) => Box::new(UploadStore {
+            endpoint: endpoint.clone(),
+        }),
+        (None, Some(dir), Some(url)) => Box::new(DirStore {
+            dir: dir.clone(),
+            url: url.clone(),
+            max_age: Duration::from_secs(config.retention_days * 24 * 60 * 60),
+        }),
+        // The config is checked when it is loaded.
+        _ => unreachable!(),
+    }
+}
+
+/// A store that writes pastes to a directory, which is served at a URL. Pastes older than
+/// `max_age` are deleted as new ones are written.
+pub struct DirStore {
+    dir: PathBuf,
+    url: String,
+    max_age: Duration,
+}
+
+impl PasteStore for DirStore {
+    fn paste(&self, text: &str) -> Fallible<String> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|_| format!("Couldn't create paste directory {}", self.dir.display()))?;
+        prune(&self.dir, self.max_age);
+
+        let name = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(NAME_LEN)
+            .collect::<String>()
+            + ".txt";
+        let path = self.dir.join(&name);
+        fs::write(&path, text).with_context(|_| format!("Couldn't write {}", path.display()))?;
+        Ok(format!("{}/{}", self.url.trim_end_matches('/'), name))
+    }
+}
+
+/// Deletes the pastes in a directory that are older than `max_age`.
+fn prune(dir: &Path, max_age: Duration) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!(
+                "Couldn't list {} to delete old pastes: {}",
+                dir.display(),
+                e
+            );
+            return;
+        }
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let name = entry.file_name();
+        if !name.to_str().map_or(false, is_paste_name) {
+            continue;
+        }
+        let age = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().unwrap_or_default());
+        if let Ok(age) = age {
+            if age > max_age {
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    warn!("Couldn't delete {}: {}", entry.path().display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Returns whether a file name is one a paste could have been written to.
+fn is_paste_name(name: &str) -> bool {
+    name.ends_with(".txt")
+        && name.len() == NAME_LEN + 4
+        && name.as_bytes()[..NAME_LEN]
+            .iter()
+            .all(u8::is_ascii_alphanumeric)
+}
+
+/// A store that uploads pastes to a paste service, which responds to a POST of the text with the
+/// URL of the paste.
+pub struct UploadStore {
+    endpoint: String,
+}
+
+impl PasteStore for UploadStore {
+    fn paste(&self, text: &str) -> Fallible<String> {
+        let tls = NativeTlsClient::new()?;
+        let mut client = hyper::Client::with_connector(HttpsConnector::new(tls));
+        client.set_read_timeout(Some(UPLOAD_TIMEOUT));
+        client.set_write_timeout(Some(UPLOAD_TIMEOUT));
+
+        let mut resp = client
+            .post(&self.endpoint)
+            .body(text)
+            .send()
+            .with_context(|_| format!("Couldn't upload to {}", self.endpoint))?;
+        let mut body = String::new();
+        resp.read_to_string(&mut body)?;
+        if !resp.status.is_success() {
+            return Err(format_err!(
+                "{} rejected a paste with {}: {}",
+                self.endpoint,
+                resp.status,
+                body.trim()
+            ));
+        }
+        Ok(body.trim().to_string())
+    }
+}
+
+/// Serves the paste directory over HTTP at the configured address, if Janus should serve it itself.
+/// Changes to the address only take effect on restart.
+pub fn serve(config: &PasteConfig) -> Fallible<()> {
+    let (dir, addr) = match (&config.dir, &config.listen) {
+        (Some(dir), Some(addr)) => (dir.clone(), addr.as_str()),
+        _ => return Ok(()),
+    };
+    let listening = Server::http(addr)
+        .with_context(|_| format!("Couldn't serve pastes at {}", addr))?
+        .handle(move |req: Request, mut resp: Response| {
+            let name = match req.uri {
+                RequestUri::AbsolutePath(ref path) => path.trim_start_matches('/').to_string(),
+                _ => String::new(),
+            };
+            // Only serve the files pastes are written to, so nothing outside the directory is.
+            let text = if is_paste_name(&name) {
+                fs::read(dir.join(&name)).ok()
+            } else {
+                None
+            };
+            match text {
+                Some(text) => {
+                    resp.headers_mut().set(ContentType::plaintext());
+                    if let Err(err) = resp.send(&text) {
+                        error!("Couldn't serve a paste: {}", err);
+                    }
+                }
+                None => *resp.status_mut() = StatusCode::NotFound,
+            }
+        })
+        .with_context(|_| format!("Couldn't serve pastes at {}", addr))?;
+    *SERVER.lock().unwrap() = Some(listening);
+    info!("Serving pastes at {}", addr);
+    Ok(())
+}
+
+/// Shortens text with more than `max_lines` lines to its first line and a link to the whole text.
+/// Lines starting with `prefix`, such as the name of the sender, have it left out of the paste.
+pub fn limit_lines(
+    store: &dyn PasteStore,
+    text: &str,
+    prefix: &str,
+    max_lines: usize,
+) -> Fallible<String> {
+    let lines = text.lines().collect::<Vec<_>>();
+    if lines.len() <= max_lines {
+        return Ok(text.to_string());
+    }
+
+    let pasted = lines
+        .iter()
+        .map(|line| line.strip_prefix(prefix).unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let url = store.paste(&pasted)?;
+    Ok(format!(
+        "{} ({} lines: {})",
+        truncate(lines[0], EXCERPT_LEN),
+        lines.len(),
+        url
+    ))
+}
+
+/// Shortens text with more than `max_lines` lines by leaving the extra lines out, for when they
+/// can't be pasted. The first line is always kept, and a note of how many lines were left out is
+/// started with `prefix` if it goes on a line of its own.
+pub fn cut_lines(text: &str, prefix: &str, max_lines: usize) -> String {
+    let lines = text.lines().collect::<Vec<_>>();
+    if lines.len() <= max_lines.max(1) {
+        return text.to_string();
+    }
+
+    let kept = max_lines.saturating_sub(1).max(1);
+    let left_out = lines.len() - kept;
+    let note = format!(
+        "({} more line{})",
+        left_out,
+        if left_out == 1 { "" } else { "s" }
+    );
+    if max_lines <= 1 {
+        format!("{} {}", lines[0], note)
+    } else {
+        format!("{}\n{}{}", lines[..kept].join("\n"), prefix, note)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// A store that remembers what was pasted, rather than putting it anywhere.
+    #[derive(Default)]
+    struct StandIn(RefCell<Vec<String>>);
+
+    impl PasteStore for StandIn {
+        fn paste(&self, text: &str) -> Fallible<String> {
+            self.0.borrow_mut().push(text.to_string());
+            Ok(format!("https://paste.example/{}", self.0.borrow().len()))
+        }
+    }
+
+    #[test]
+    fn short_messages_arent_pasted() {
+        let store = StandIn::default();
+        let text = "nick: one\nnick: two";
+        assert_eq!(limit_lines(&store, text, "nick: ", 2).unwrap(), text);
+        assert!(store.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn long_messages_are_pasted() {
+        let store = StandIn::default();
+        let text = "nick: one\nnick: two\nnick: three";
+        assert_eq!(
+            limit_lines(&store, text, "nick: ", 2).unwrap(),
+            "nick: one (3 lines: https://paste.example/1)"
+        );
+        assert_eq!(*store.0.borrow(), vec!["one\ntwo\nthree"]);
+    }
+
+    #[test]
+    fn the_first_line_is_truncated() {
+        let store = StandIn::default();
+        let text = format!("{}\nmore", "x".repeat(200));
+        let shortened = limit_lines(&store, &text, "", 1).unwrap();
+        assert!(shortened.starts_with(&format!("{}…", "x".repeat(EXCERPT_LEN))));
+        assert_eq!(*store.0.borrow(), vec![text]);
+    }
+
+    #[test]
+    fn old_pastes_are_deleted() {
+        let dir = std::env::temp_dir().join(format!("janus-pastes-{}", std::process::id()));
+        let store = DirStore {
+            dir: dir.clone(),
+            url: "https://paste.example/".to_string(),
+            max_age: Duration::from_secs(0),
+        };
+        let old = store.paste("old").unwrap();
+        fs::write(dir.join("notes.txt"), "not a paste").unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let new = store.paste("new").unwrap();
+
+        let mut names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        fs::remove_dir_all(&dir).unwrap();
+        let mut expected = vec![new.rsplit('/').next().unwrap(), "notes.txt"];
+        expected.sort();
+        assert!(old != new);
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn cut_lines_keeps_short_messages() {
+        let text = "nick: one\nnick: two";
+        assert_eq!(cut_lines(text, "nick: ", 2), text);
+        assert_eq!(cut_lines("nick: one", "nick: ", 0), "nick: one");
+    }
+
+    #[test]
+    fn cut_lines_notes_what_was_left_out() {
+        let text = "nick: one\nnick: two\nnick: three";
+        assert_eq!(
+            cut_lines(text, "nick: ", 2),
+            "nick: one\nnick: (2 more lines)"
+        );
+        assert_eq!(
+            cut_lines(&format!("{}\nnick: four", text), "nick: ", 3),
+            "nick: one\nnick: two\nnick: (2 more lines)"
+        );
+        assert_eq!(
+            cut_lines("nick: one\nnick: two", "nick: ", 1),
+            "nick: one (1 more line)"
+        );
+        assert_eq!(cut_lines(text, "nick: ", 0), "nick: one (2 more lines)");
+    }
+}
@@ -0,0 +1,203 @@
+//! A persistent record of which Discord messages correspond to which IRC lines, so that edits,
+//! deletions, replies and reactions can be correlated across the relay.
+//!
+//! Entries are kept in memory and appended to a file of JSON lines in the state directory. Entries
+//! older than the retention period are pruned periodically, at which point the file is rewritten.
+
+use failure::{Fallible, ResultExt};
+use lazy_static::lazy_static;
+use log::{info, warn};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::DEFAULT_NETWORK, log_err};
+
+lazy_static! {
+    static ref STORE: Mutex<Option<MessageStore>> = Mutex::new(None);
+}
+
+/// The name of the file entries are stored in.
+const STORE_FILE: &str = "messages.jsonl";
+
+/// How often to prune old entries.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A record of a message that was relayed between Discord and IRC.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Entry {
+    /// The ID of the Discord channel.
+    pub discord_channel: u64,
+
+    /// The ID of the Discord message.
+    pub discord_message: u64,
+
+    /// The name of the IRC network the channel is on. Entries recorded before there could be
+    /// several networks are on the default one.
+    #[serde(default = "default_irc_network")]
+    pub irc_network: String,
+
+    /// The name of the IRC channel.
+    pub irc_channel: String,
+
+    /// The display name of the sender, on the side the message was sent from.
+    pub sender: String,
+
+    /// Whether the message was sent from IRC, rather than Discord.
+    pub from_irc: bool,
+
+    /// The text of the message, as it was sent on its original side.
+    pub content: String,
+
+    /// When the message was relayed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl Entry {
+    /// Returns the current time, in the form used for `timestamp`.
+    pub fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+fn default_irc_network() -> String {
+    DEFAULT_NETWORK.to_string()
+}
+
+/// The store of relayed messages.
+pub struct MessageStore {
+    path: PathBuf,
+    entries: Vec<Entry>,
+    max_age: Duration,
+    last_pruned: SystemTime,
+}
+
+impl MessageStore {
+    /// Opens the store in the given directory, creating it if needed.
+    pub fn init(dir: impl AsRef<Path>, max_age: Duration) -> Fallible<()> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)
+            .with_context(|_| format!("Couldn't create state directory {}", dir.display()))?;
+
+        let path = dir.join(STORE_FILE);
+        let entries = if path.exists() {
+            MessageStore::load(&path)?
+        } else {
+            Vec::new()
+        };
+        let mut store = MessageStore {
+            path,
+            entries,
+            max_age,
+            last_pruned: SystemTime::now(),
+        };
+        store.prune()?;
+        info!("Loaded {} relayed messages", store.entries.len());
+
+        *STORE.lock().unwrap() = Some(store);
+        Ok(())
+    }
+
+    /// Returns the entries for the given Discord message.
+    pub fn by_discord_message(id: u64) -> Vec<Entry> {
+        MessageStore::with(|store| {
+            store
+                .entries
+                .iter()
+                .filter(|e| e.discord_message == id)
+                .cloned()
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    /// Records a relayed message. Errors are logged rather than returned, since failing to
+    /// record a message shouldn't stop it from being relayed.
+    pub fn record(entry: Entry) {
+        let result = MessageStore::with(|store| -> Fallible<()> {
+            store.append(&entry)?;
+            store.entries.push(entry);
+            if store.last_pruned.elapsed().unwrap_or_default() >= PRUNE_INTERVAL {
+                store.prune()?;
+            }
+            Ok(())
+        });
+        if let Some(Err(err)) = result {
+            log_err(err);
+        }
+    }
+
+    /// Runs a function with the store, if it has been initialized.
+    fn with<T>(func: impl FnOnce(&mut MessageStore) -> T) -> Option<T> {
+        STORE.lock().unwrap().as_mut().map(func)
+    }
+
+    /// Appends an entry to the file.
+    fn append(&self, entry: &Entry) -> Fallible<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Reads the entries from a file, skipping any that can't be parsed.
+    fn load(path: &Path) -> Fallible<Vec<Entry>> {
+        let file = File::open(path)
+            .with_context(|_| format!("Couldn't open message store {}", path.display()))?;
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => warn!("Skipping corrupt message store entry: {}", err),
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes entries older than the maximum age, and rewrites the file to match.
+    fn prune(&mut self) -> Fallible<()> {
+        let cutoff = Entry::now().saturating_sub(self.max_age.as_secs());
+        self.entries.retain(|e| e.timestamp >= cutoff);
+        self.last_pruned = SystemTime::now();
+
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        {
+            let mut file = BufWriter::new(File::create(&tmp_path)?);
+            for entry in &self.entries {
+                serde_json::to_writer(&mut file, entry)?;
+                file.write_all(b"\n")?;
+            }
+            file.flush()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_entries_are_on_the_default_network() {
+        let entry: Entry = serde_json::from_str(
+            r##"{"discord_channel":1,"discord_message":2,"irc_channel":"#chan","sender":"alice",
+                "from_irc":false,"content":"hi","timestamp":3}"##,
+        )
+        .unwrap();
+        assert_eq!(entry.irc_network, DEFAULT_NETWORK);
+        assert_eq!(entry.irc_channel, "#chan");
+    }
+}